@@ -0,0 +1,156 @@
+// Logging every request to `.access_log`, in the Common or Combined Log
+// Format, separately from everything else.
+
+use crate::{
+    config::{AccessLog, AccessLogFormat},
+    listen::Connection,
+    logging::LogFile,
+};
+
+use chrono::{DateTime, Local};
+use futures_util::StreamExt;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, REFERER, USER_AGENT};
+use hyper::{body::HttpBody, Body, Request, Response};
+use log::error;
+use std::{fmt::Write as _, io::Write as _, sync::OnceLock, time::Instant};
+
+static ACCESS_LOG: OnceLock<(LogFile, AccessLogFormat)> = OnceLock::new();
+
+pub fn open(access_log: Option<&AccessLog>) -> Result<(), anyhow::Error> {
+    if let Some(access_log) = access_log {
+        let _ = ACCESS_LOG.set((LogFile::open(&access_log.file)?, access_log.format));
+    }
+    Ok(())
+}
+
+pub fn reopen() -> std::io::Result<()> {
+    match ACCESS_LOG.get() {
+        Some((file, _)) => file.reopen(),
+        None => Ok(()),
+    }
+}
+
+// A request, which is logged once its response has been sent (or the client
+// has gone away).
+pub struct Entry {
+    file: LogFile,
+    format: AccessLogFormat,
+    time: DateTime<Local>,
+    started: Instant,
+    // All but the catcher are already escaped.
+    remote_host: String,
+    user: String,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+    status: u16,
+    bytes: u64,
+    catcher: Option<String>,
+}
+
+// Only if there is an access log.
+pub fn start(conn: &Connection, request: &Request<Body>) -> Option<Entry> {
+    let (file, format) = ACCESS_LOG.get()?;
+    let header = |name| request.headers().get(name).map(HeaderValue::as_bytes);
+    Some(Entry {
+        file: file.clone(),
+        format: *format,
+        time: Local::now(),
+        started: Instant::now(),
+        remote_host: conn
+            .remote_addr
+            .map_or_else(|| "-".to_owned(), |addr| addr.ip().to_string()),
+        // The common name of the client certificate is the closest thing to
+        // an authenticated user there is.
+        user: conn
+            .client_cert
+            .as_ref()
+            .and_then(|cert| cert.common_name.as_deref())
+            .map_or_else(|| "-".to_owned(), |name| escape(name.as_bytes())),
+        request_line: escape(
+            format!(
+                "{} {} {:?}",
+                request.method(),
+                request.uri(),
+                request.version()
+            )
+            .as_bytes(),
+        ),
+        referer: header(REFERER).map_or_else(|| "-".to_owned(), escape),
+        user_agent: header(USER_AGENT).map_or_else(|| "-".to_owned(), escape),
+        status: 0,
+        bytes: 0,
+        catcher: None,
+    })
+}
+
+impl Entry {
+    // Counts the bytes of the body of `response` as they are sent.
+    pub fn finish(mut self, response: Response<Body>, catcher: Option<String>) -> Response<Body> {
+        self.status = response.status().as_u16();
+        self.catcher = catcher;
+        let (mut parts, body) = response.into_parts();
+        // The wrapped body doesn't know its size, so hyper wouldn't set it.
+        if let Some(len) = body.size_hint().exact() {
+            parts.headers.entry(CONTENT_LENGTH).or_insert(len.into());
+        }
+        let body = body.map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                self.sent(chunk.len());
+            }
+            chunk
+        });
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn sent(&mut self, len: usize) {
+        self.bytes += len as u64;
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let mut line = format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            self.remote_host,
+            self.user,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request_line,
+            self.status,
+            match self.bytes {
+                0 => "-".to_owned(),
+                bytes => bytes.to_string(),
+            }
+        );
+        if self.format == AccessLogFormat::Combined {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\" {:.3} \"{}\"",
+                self.referer,
+                self.user_agent,
+                self.started.elapsed().as_secs_f64(),
+                self.catcher
+                    .as_deref()
+                    .map_or_else(|| "-".to_owned(), |path| escape(path.as_bytes()))
+            );
+        }
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            error!("Could not write to access log: {}", e);
+        }
+    }
+}
+
+// Escapes quotes, backslashes and anything that isn't printable ASCII as
+// `\xHH`, the way nginx does.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte) {
+            let _ = write!(escaped, "\\x{:02X}", byte);
+        } else {
+            escaped.push(byte as char);
+        }
+    }
+    escaped
+}
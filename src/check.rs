@@ -0,0 +1,397 @@
+// Finding problems with a configuration before they show up as failed
+// requests.
+
+use crate::config::{self, Catcher, Config, RateLimit, Secret, Validate};
+
+use hyper::Method;
+use std::{
+    env,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+pub struct Problem {
+    // The index of the catcher the problem is with, if any.
+    pub catcher: Option<usize>,
+    pub message: String,
+    pub severity: Severity,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    // Worth a look, but it might be intended.
+    Warning,
+    // Requests will fail because of it.
+    Error,
+    // webhookee refuses to start because of it.
+    Fatal,
+}
+
+const STANDARD_METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::CONNECT,
+    Method::OPTIONS,
+    Method::TRACE,
+    Method::PATCH,
+];
+
+// Shell builtins and keywords that a command might start with.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "{", "(", "!", "case", "cd", "command", "echo", "eval", "exec", "exit",
+    "export", "false", "for", "if", "printf", "read", "set", "test", "true", "umask", "unset",
+    "until", "while",
+];
+
+// Whether some request could reach both catchers, as far as listeners go.
+fn share_listener(a: &Catcher, b: &Catcher) -> bool {
+    a.listen.is_empty() || b.listen.is_empty() || a.listen.iter().any(|l| b.listen.contains(l))
+}
+
+// Whether `earlier` gets every request `later` could, as far as listeners go.
+fn covers_listeners(earlier: &Catcher, later: &Catcher) -> bool {
+    earlier.listen.is_empty()
+        || (!later.listen.is_empty() && later.listen.iter().all(|l| earlier.listen.contains(l)))
+}
+
+// The longest `.shutdown_timeout`, a week.
+const MAX_SHUTDOWN_TIMEOUT: f64 = 7.0 * 86400.0;
+
+// The slowest rate limit, one request a day.
+const MIN_RATE: f64 = 1.0 / 86400.0;
+
+fn valid_rate_limit(limit: &RateLimit) -> bool {
+    limit.rate.is_finite() && limit.rate >= MIN_RATE && limit.burst != Some(0)
+}
+
+pub fn problems(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let listeners = config.listeners();
+    if listeners.is_empty() {
+        // Sockets from systemd aren't in the configuration, so this is only
+        // an error at startup if there are none of those either.
+        problems.push(Problem {
+            catcher: None,
+            message: "Nothing to listen on, unless sockets are passed by systemd".to_owned(),
+            severity: Severity::Warning,
+        });
+    }
+    let is_listener =
+        |name: &String| name.starts_with("systemd:") || listeners.iter().any(|l| l.name() == *name);
+    for listener in config.tls.iter().flat_map(|tls| &tls.listen) {
+        if !is_listener(listener) {
+            problems.push(Problem {
+                catcher: None,
+                message: format!(
+                    "`.tls.listen` has `{}`, which is not in `.listen`",
+                    listener
+                ),
+                severity: Severity::Fatal,
+            });
+        }
+    }
+    for listener in config.metrics.iter().flat_map(|metrics| &metrics.listen) {
+        if !is_listener(listener) {
+            problems.push(Problem {
+                catcher: None,
+                message: format!(
+                    "`.metrics.listen` has `{}`, which is not in `.listen`",
+                    listener
+                ),
+                severity: Severity::Fatal,
+            });
+        }
+    }
+    if !(0.0..=MAX_SHUTDOWN_TIMEOUT).contains(&config.shutdown_timeout) {
+        problems.push(Problem {
+            catcher: None,
+            message: "`.shutdown_timeout` must be a number of seconds, up to a week".to_owned(),
+            severity: Severity::Fatal,
+        });
+    }
+    if config
+        .rate_limit
+        .is_some_and(|limit| !valid_rate_limit(&limit))
+    {
+        problems.push(Problem {
+            catcher: None,
+            message:
+                "`.rate_limit` must allow at least one request a day, and have a positive `burst`"
+                    .to_owned(),
+            severity: Severity::Fatal,
+        });
+    }
+    for (i, catcher) in config.catchers.iter().enumerate() {
+        let mut problem = |message: String, severity: Severity| {
+            problems.push(Problem {
+                catcher: Some(i),
+                message,
+                severity,
+            })
+        };
+
+        if catcher.run_async && catcher.response != config::Respond::Buffered {
+            problem(
+                format!(
+                    "Catcher for `{}` is async, so it can't stream its output",
+                    catcher.path
+                ),
+                Severity::Fatal,
+            );
+        }
+
+        if let Some(retry) = &catcher.retry {
+            if !catcher.run_async {
+                problem(
+                    format!(
+                        "Catcher for `{}` has a retry policy but is not async",
+                        catcher.path
+                    ),
+                    Severity::Fatal,
+                );
+            }
+            if retry.max_attempts == 0 {
+                problem(
+                    format!(
+                        "Catcher for `{}` must have at least 1 attempt",
+                        catcher.path
+                    ),
+                    Severity::Fatal,
+                );
+            }
+            let valid_secs = |secs: f64| secs.is_finite() && secs >= 0.0;
+            if !valid_secs(retry.backoff) || !valid_secs(retry.max_backoff) {
+                problem(
+                    format!(
+                        "Catcher for `{}` has an invalid retry backoff",
+                        catcher.path
+                    ),
+                    Severity::Fatal,
+                );
+            }
+        }
+
+        if catcher
+            .rate_limit
+            .is_some_and(|limit| !valid_rate_limit(&limit))
+        {
+            problem(
+                format!(
+                    "Catcher for `{}` must have a rate limit of at least one request a day, and a positive `burst`",
+                    catcher.path
+                ),
+                Severity::Fatal,
+            );
+        }
+
+        for method in &catcher.methods {
+            if !STANDARD_METHODS.contains(&method.0) {
+                problem(
+                    format!(
+                        "`{}` is not a standard HTTP method (note that methods are case-sensitive)",
+                        method.0
+                    ),
+                    Severity::Warning,
+                );
+            }
+        }
+
+        for listener in &catcher.listen {
+            if !is_listener(listener) {
+                problem(
+                    format!(
+                        "Catcher for `{}` is restricted to `{}`, which is not in `.listen`",
+                        catcher.path, listener
+                    ),
+                    Severity::Fatal,
+                );
+            }
+        }
+
+        // Catchers from different files can't be told apart by order, so
+        // it's an error for them to overlap.
+        let conflicting = config.catchers[..i].iter().find(|other| {
+            other.file != catcher.file
+                && other.path == catcher.path
+                && share_listener(other, catcher)
+                && other.methods.iter().any(|m| catcher.methods.contains(m))
+        });
+        let earlier = config.catchers[..i]
+            .iter()
+            .position(|other| other.path == catcher.path && share_listener(other, catcher));
+        if let Some(other) = conflicting {
+            problem(
+                format!(
+                    "Catcher for `{}` conflicts with the one in {}",
+                    catcher.path,
+                    config.files[other.file].display()
+                ),
+                Severity::Fatal,
+            );
+        } else if let Some(j) = earlier {
+            let shadowed: Vec<_> = catcher
+                .methods
+                .iter()
+                .filter(|method| {
+                    config.catchers[..i].iter().any(|other| {
+                        other.path == catcher.path
+                            && covers_listeners(other, catcher)
+                            && other.methods.contains(method)
+                    })
+                })
+                .map(|method| method.0.as_str())
+                .collect();
+            if shadowed.len() == catcher.methods.len() {
+                problem(
+                    format!(
+                        "Catcher for `{}` will never be used, since catcher #{} comes first",
+                        catcher.path,
+                        j + 1
+                    ),
+                    Severity::Warning,
+                );
+            } else if !shadowed.is_empty() {
+                problem(
+                    format!(
+                        "{} requests to `{}` will never reach this catcher, since an earlier one handles them",
+                        shadowed.join(", "),
+                        catcher.path
+                    ),
+                    Severity::Warning,
+                );
+            } else {
+                problem(
+                    format!(
+                        "Catcher #{} also has the path `{}`, consider merging them",
+                        j + 1,
+                        catcher.path
+                    ),
+                    Severity::Warning,
+                );
+            }
+        }
+
+        if config.is_builtin_path(&catcher.path, &catcher.listen)
+            && catcher
+                .methods
+                .iter()
+                .any(|method| method.0 == Method::GET || method.0 == Method::HEAD)
+        {
+            problem(
+                format!(
+                    "GET and HEAD requests to `{}` are handled by webhookee itself, change `.health` or `.metrics` to move it",
+                    catcher.path
+                ),
+                Severity::Fatal,
+            );
+        }
+
+        if let Some(message) = check_command(&catcher.run) {
+            problem(format!("`.run` command {}", message), Severity::Error);
+        }
+        match &catcher.validate {
+            Validate::Dont => {}
+            Validate::Command(cmd) => {
+                if let Some(message) = check_command(cmd) {
+                    problem(format!("`.validate` command {}", message), Severity::Error);
+                }
+            }
+            Validate::ClientCert(_) => {
+                if config
+                    .tls
+                    .as_ref()
+                    .is_none_or(|tls| tls.client_ca.is_none())
+                {
+                    problem(
+                        format!(
+                            "Catcher for `{}` validates client certificates, but `.tls.client_ca` is not set",
+                            catcher.path
+                        ),
+                        Severity::Fatal,
+                    );
+                }
+            }
+            Validate::GitHub(secret) => {
+                if let Secret::Var(key_var) = secret {
+                    if env::var(key_var).is_err() {
+                        problem(
+                            format!(
+                                "Environment variable {} for the GitHub secret is not set or is not valid UTF-8",
+                                key_var
+                            ),
+                            Severity::Error,
+                        );
+                    }
+                }
+            }
+        }
+    }
+    problems
+}
+
+// Checks that the program a shell command starts with can be run, as far as
+// that can be told without running it. Returns what is wrong if not.
+fn check_command(cmd: &str) -> Option<String> {
+    let program = cmd.split_whitespace().next()?;
+    // Anything more complicated than a plain word is up to the shell.
+    if SHELL_BUILTINS.contains(&program) || program.contains(|c| "$`\"'(){}=;|&<>*?".contains(c)) {
+        return None;
+    }
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let path = match program.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir()?.join(rest),
+            None => PathBuf::from(program),
+        };
+        if !path.exists() {
+            Some(format!("`{}` does not exist", program))
+        } else if !is_executable(&path) {
+            Some(format!("`{}` is not executable", program))
+        } else {
+            None
+        }
+    } else {
+        let found = env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+        });
+        if found {
+            None
+        } else {
+            Some(format!("`{}` could not be found in $PATH", program))
+        }
+    }
+}
+
+// Finds where each catcher is in the configuration source, as a 1-based
+// (line, column) of its `path` value. This is a best effort: it looks for
+// each path in order, right after a `:` or `=`.
+pub fn locate_catchers<'a>(
+    src: &str,
+    catchers: impl IntoIterator<Item = &'a Catcher>,
+) -> Vec<Option<(usize, usize)>> {
+    let mut locations = Vec::new();
+    let mut start = 0;
+    for catcher in catchers {
+        let found = src[start..]
+            .match_indices(catcher.path.as_str())
+            .map(|(offset, _)| start + offset)
+            .find(|&offset| {
+                let before = src[..offset].trim_end_matches(['"', '\'']);
+                before.trim_end().ends_with([':', '='])
+            });
+        match found {
+            Some(offset) => {
+                locations.push(Some(config::line_column(src, offset)));
+                start = offset + catcher.path.len();
+            }
+            None => locations.push(None),
+        }
+    }
+    locations
+}
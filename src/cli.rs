@@ -0,0 +1,544 @@
+// Subcommands for inspecting, managing and testing a webhookee instance.
+
+use crate::{
+    check,
+    config::{self, Listen, Validate},
+    history::{Entry, History},
+    jobs::Jobs,
+    payload::Payload,
+    queue::{self, DeadJob},
+    tls, validate, CatcherReturn, State,
+};
+
+use anyhow::Context;
+use chrono::{Local, TimeZone};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderName, HeaderValue, HOST},
+    Body, Method, Request, Response, Uri,
+};
+use log::{info, warn};
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use structopt::StructOpt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Inspect and re-run async jobs that failed for good
+    DeadLetter(DeadLetter),
+    /// List the most recent requests to catchers, newest first
+    History {
+        /// How many requests to list
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: u64,
+    },
+    /// Show everything recorded about a request to a catcher
+    Show { id: String },
+    /// Handle a recorded request again, as if it had just been received
+    Replay {
+        /// The ID of a request in the history, or a file containing a request payload
+        source: String,
+        /// Run the catcher without validating the request first
+        #[structopt(long)]
+        skip_validation: bool,
+    },
+    /// Check the configuration for problems, exiting with an error if there are any
+    Check {
+        /// Instead, show which catcher would handle a request
+        #[structopt(long, number_of_values = 2, value_names = &["METHOD", "PATH"])]
+        dry_run: Option<Vec<String>>,
+    },
+    /// Send a request to a running instance, signed the way its catcher expects
+    Send {
+        /// The path of the catcher to send the request to
+        path: String,
+        /// The HTTP method to use
+        #[structopt(short = "X", long, default_value = "POST")]
+        method: String,
+        /// Extra headers to send, as `Name: value`
+        #[structopt(short = "H", long = "header")]
+        headers: Vec<String>,
+        /// File to read the request body from (`-` for standard input)
+        #[structopt(short = "d", long)]
+        body_file: Option<PathBuf>,
+        /// Where the instance is listening, as `http://` or `https://` and an address (defaults to the first address the catcher is on)
+        #[structopt(long)]
+        url: Option<String>,
+        #[structopt(flatten)]
+        tls: SendTls,
+    },
+}
+
+// How `send` connects over HTTPS.
+#[derive(StructOpt, Debug)]
+pub struct SendTls {
+    /// File with the CA certificates to trust for HTTPS (defaults to the ones in `.tls.cert`)
+    #[structopt(long)]
+    cacert: Option<PathBuf>,
+    /// File with a client certificate to present over HTTPS
+    #[structopt(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// File with the private key of `--cert`
+    #[structopt(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DeadLetter {
+    /// List the failed jobs, oldest first
+    List,
+    /// Show everything recorded about a failed job
+    Show { id: String },
+    /// Run a failed job again from scratch, using the current configuration
+    Retry { id: String },
+}
+
+// Formats seconds since the Unix epoch in local time.
+fn format_time(secs: u64) -> String {
+    match Local.timestamp_opt(secs as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => secs.to_string(),
+    }
+}
+
+pub async fn run(
+    cmd: Command,
+    config_path: Option<PathBuf>,
+    data_dir: PathBuf,
+) -> Result<(), anyhow::Error> {
+    match cmd {
+        Command::DeadLetter(cmd) => dead_letter(cmd, config_path, data_dir).await,
+        Command::History { limit } => {
+            let history = History::open(&data_dir, Default::default())?;
+            for Entry {
+                id,
+                received_at,
+                validation,
+                status,
+                payload,
+                ..
+            } in history.recent(limit)?
+            {
+                let status = match status {
+                    Some(status) => status,
+                    None if validation == "passed" => "not finished".to_owned(),
+                    None => "not run".to_owned(),
+                };
+                println!(
+                    "{}  {}  {} {}  validation {}, {}",
+                    id,
+                    format_time(received_at),
+                    payload.method.0,
+                    payload.path,
+                    validation,
+                    status
+                );
+            }
+            Ok(())
+        }
+        Command::Show { id } => {
+            let history = History::open(&data_dir, Default::default())?;
+            let entry = history
+                .get(&id)?
+                .ok_or_else(|| anyhow::Error::msg(format!("No request {} in history", id)))?;
+            let entry_str =
+                serde_json::to_string_pretty(&entry).context("Could not serialize entry")?;
+            println!("{}", entry_str);
+            Ok(())
+        }
+        Command::Replay {
+            source,
+            skip_validation,
+        } => replay(source, skip_validation, config_path, data_dir).await,
+        Command::Check { dry_run } => check(dry_run, config_path),
+        Command::Send {
+            path,
+            method,
+            headers,
+            body_file,
+            url,
+            tls,
+        } => send(path, method, headers, body_file, url, tls, config_path).await,
+    }
+}
+
+async fn replay(
+    source: String,
+    skip_validation: bool,
+    config_path: Option<PathBuf>,
+    data_dir: PathBuf,
+) -> Result<(), anyhow::Error> {
+    let state: &'static State = Box::leak(Box::new(State::new(
+        config::Handle::open(config_path)?,
+        &data_dir,
+    )?));
+    let req_payload: Payload = if Path::new(&source).is_file() {
+        let payload_str =
+            fs::read(&source).with_context(|| format!("Could not read {}", source))?;
+        serde_json::from_slice(&payload_str).context("JSON did not fit data format")?
+    } else {
+        let entry = state
+            .history
+            .get(&source)?
+            .ok_or_else(|| anyhow::Error::msg(format!("No request {} in history", source)))?;
+        if entry.body_truncated {
+            anyhow::bail!(
+                "Only the start of the body of {} is in the history, so it can't be replayed",
+                source
+            );
+        }
+        entry.payload
+    };
+    let catcher = state
+        .config
+        .get()
+        .find_catcher(&req_payload.method.0, &req_payload.path)
+        .cloned()
+        .ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "No catcher for `{}` to `{}`",
+                req_payload.method.0, req_payload.path
+            ))
+        })?;
+    let id = queue::new_id();
+    info!(
+        "Replaying `{}` to `{}` as {}",
+        req_payload.method.0, req_payload.path, id
+    );
+    match crate::catch_payload(state, catcher, id.clone(), req_payload, !skip_validation).await? {
+        CatcherReturn::Allowed(mut body) => {
+            let mut stdout = tokio::io::stdout();
+            while let Some(chunk) = body.data().await {
+                stdout
+                    .write_all(&chunk.context("Could not read output of .run command")?)
+                    .await
+                    .context("Could not write to standard output")?;
+                stdout
+                    .flush()
+                    .await
+                    .context("Could not write to standard output")?;
+            }
+            Ok(())
+        }
+        CatcherReturn::Accepted(job) => {
+            if job.await.context("Job panicked")? {
+                Ok(())
+            } else {
+                anyhow::bail!("Job {} failed", id)
+            }
+        }
+        CatcherReturn::Denied => anyhow::bail!("The request failed validation"),
+    }
+}
+
+async fn dead_letter(
+    cmd: DeadLetter,
+    config_path: Option<PathBuf>,
+    data_dir: PathBuf,
+) -> Result<(), anyhow::Error> {
+    let open_jobs = |history| Jobs::open(&data_dir, Arc::new(history), Arc::default());
+    match cmd {
+        DeadLetter::List => {
+            let jobs = open_jobs(History::open(&data_dir, Default::default())?)?;
+            let mut dead_jobs = jobs.dead_letters().all()?;
+            dead_jobs.sort_by_key(|dead_job| dead_job.last_failure.failed_at);
+            for DeadJob { job, last_failure } in dead_jobs {
+                println!(
+                    "{}  {}  {} {}  {} attempt(s), {}",
+                    job.id,
+                    format_time(last_failure.failed_at),
+                    job.payload.method.0,
+                    job.payload.path,
+                    job.attempts,
+                    last_failure.status
+                );
+            }
+            Ok(())
+        }
+        DeadLetter::Show { id } => {
+            let jobs = open_jobs(History::open(&data_dir, Default::default())?)?;
+            let dead_job = jobs.dead_letters().get(&id)?;
+            let dead_job_str = serde_json::to_string_pretty(&dead_job)
+                .context("Could not serialize dead letter")?;
+            println!("{}", dead_job_str);
+            Ok(())
+        }
+        DeadLetter::Retry { id } => {
+            let config = config::get(config_path)?;
+            let jobs = open_jobs(History::open(&data_dir, config.history.clone())?)?;
+            let payload = jobs.dead_letters().get(&id)?.job.payload;
+            let catcher = config
+                .find_catcher(&payload.method.0, &payload.path)
+                .filter(|catcher| catcher.run_async)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!(
+                        "No async catcher for `{}` to `{}`",
+                        payload.method.0, payload.path
+                    ))
+                })?;
+            if jobs.retry_dead(catcher, &id).await? {
+                println!("Job {} succeeded", id);
+                Ok(())
+            } else {
+                anyhow::bail!("Job {} failed again", id)
+            }
+        }
+    }
+}
+
+async fn send(
+    path: String,
+    method: String,
+    headers: Vec<String>,
+    body_file: Option<PathBuf>,
+    url: Option<String>,
+    tls: SendTls,
+    config_path: Option<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let config = config::get(config_path)?;
+    let method = Method::from_str(&method.to_uppercase()).context("Not a valid HTTP verb")?;
+    let catcher = config
+        .find_catcher(&method, &path)
+        .ok_or_else(|| anyhow::Error::msg(format!("No catcher for `{}` to `{}`", method, path)))?;
+    let body = match body_file {
+        Some(body_file) if body_file == Path::new("-") => {
+            let mut body = Vec::new();
+            tokio::io::stdin()
+                .read_to_end(&mut body)
+                .await
+                .context("Could not read standard input")?;
+            body
+        }
+        Some(body_file) => fs::read(&body_file)
+            .with_context(|| format!("Could not read {}", body_file.display()))?,
+        None => Vec::new(),
+    };
+    let url = match url {
+        Some(url) => url,
+        None => {
+            // Requests to the catcher have to go through one of its listeners.
+            let listeners = config.listeners();
+            let listener = match catcher.listen.first() {
+                Some(name) => listeners.iter().find(|l| l.name() == *name),
+                None => listeners.first(),
+            };
+            let listener = match listener {
+                Some(Listen::Tcp(addr)) => addr,
+                Some(Listen::Unix(_)) => anyhow::bail!(
+                    "Can't send requests over a Unix socket, use `--url` to give a TCP address"
+                ),
+                None => anyhow::bail!("Nothing to listen on, `.port` or `.listen` must be given"),
+            };
+            let https = config
+                .tls
+                .as_ref()
+                .is_some_and(|tls| tls.listen.is_empty() || tls.listen.contains(listener));
+            let scheme = if https { "https" } else { "http" };
+            format!("{}://{}", scheme, local_address(listener))
+        }
+    };
+    let uri = Uri::from_str(&format!("{}{}", url.trim_end_matches('/'), path))
+        .with_context(|| format!("`{}` is not a valid URL", url))?;
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => anyhow::bail!("`{}` does not start with `http://` or `https://`", url),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::Error::msg(format!("`{}` has no host", url)))?
+        .to_owned();
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri.path_and_query().map_or("/", |pq| pq.as_str()))
+        .header(HOST, uri.authority().map_or(&*host, |a| a.as_str()))
+        .body(Body::empty())
+        .context("Could not build request")?;
+    for header in headers {
+        let (name, value) = header.split_once(':').ok_or_else(|| {
+            anyhow::Error::msg(format!(
+                "Header `{}` is not of the form `Name: value`",
+                header
+            ))
+        })?;
+        request.headers_mut().append(
+            HeaderName::from_str(name.trim()).context("Not a valid header name")?,
+            HeaderValue::from_str(value.trim()).context("Not a valid header value")?,
+        );
+    }
+    let has_cert = matches!(catcher.validate, Validate::ClientCert(_)) && tls.cert.is_some();
+    if !validate::sign(catcher, request.headers_mut(), &body)? && !has_cert {
+        warn!(
+            "This catcher validates requests with {}, so the request can't be signed",
+            describe_validate(&catcher.validate)
+        );
+    }
+    *request.body_mut() = Body::from(body);
+
+    // IPv6 addresses are in brackets in URLs, but not for connecting.
+    let addr = host.trim_start_matches('[').trim_end_matches(']');
+    let tcp = TcpStream::connect((addr, port))
+        .await
+        .with_context(|| format!("Could not connect to {}", url))?;
+    let response = if https {
+        let roots = match (&tls.cacert, &config.tls) {
+            (Some(cacert), _) => cacert.clone(),
+            (None, Some(config_tls)) => config_tls.cert.clone(),
+            (None, None) => anyhow::bail!("Use `--cacert` to give the CA certificates to trust"),
+        };
+        let client_cert = tls.cert.as_deref().zip(tls.key.as_deref());
+        let connector = tls::connector(&roots, client_cert)?;
+        let server_name = ServerName::try_from(addr.to_owned())
+            .with_context(|| format!("`{}` is not a valid server name", addr))?;
+        let stream = connector
+            .connect(server_name, tcp)
+            .await
+            .context("TLS handshake failed")?;
+        send_over(stream, request).await?
+    } else {
+        send_over(tcp, request).await?
+    };
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .context("Could not read response")?;
+    tokio::io::stdout()
+        .write_all(&body)
+        .await
+        .context("Could not write to standard output")?;
+    if status.is_success() {
+        info!("Got {}", status);
+        Ok(())
+    } else {
+        anyhow::bail!("Got {}", status)
+    }
+}
+
+async fn send_over<IO>(io: IO, request: Request<Body>) -> Result<Response<Body>, anyhow::Error>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::handshake(io)
+        .await
+        .context("Could not send request")?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            warn!("Connection failed: {}", e);
+        }
+    });
+    sender
+        .send_request(request)
+        .await
+        .context("Could not send request")
+}
+
+// Where to connect to reach a listener, which might be on all addresses.
+fn local_address(listener: &str) -> String {
+    for any in ["0.0.0.0:", "[::]:"] {
+        if let Some(port) = listener.strip_prefix(any) {
+            return format!("localhost:{}", port);
+        }
+    }
+    listener.to_owned()
+}
+
+fn check(dry_run: Option<Vec<String>>, config_path: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    let config = match config::load(&config::path(config_path)?) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{:#}", e);
+            anyhow::bail!("The configuration could not be loaded");
+        }
+    };
+    let mut locations = vec![None; config.catchers.len()];
+    for (file, path) in config.files.iter().enumerate() {
+        let src = fs::read_to_string(path)
+            .with_context(|| format!("Could not open configuration file {}", path.display()))?;
+        let in_file: Vec<_> = (0..config.catchers.len())
+            .filter(|&i| config.catchers[i].file == file)
+            .collect();
+        let found = check::locate_catchers(&src, in_file.iter().map(|&i| &*config.catchers[i]));
+        for (i, location) in in_file.into_iter().zip(found) {
+            locations[i] = location;
+        }
+    }
+    let location = |i: usize| {
+        let path = config.files[config.catchers[i].file].display();
+        match locations[i] {
+            Some((line, column)) => format!("{}:{}:{}", path, line, column),
+            None => format!("{} (catcher #{})", path, i + 1),
+        }
+    };
+
+    if let Some(dry_run) = dry_run {
+        let method = Method::from_str(&dry_run[0]).context("Not a valid HTTP verb")?;
+        let path = &dry_run[1];
+        match config.catchers.iter().position(|catcher| {
+            catcher.path == *path && catcher.methods.iter().any(|m| *m == method)
+        }) {
+            Some(i) => {
+                let catcher = &config.catchers[i];
+                println!(
+                    "`{}` to `{}` would be handled by the catcher at {}",
+                    method,
+                    path,
+                    location(i)
+                );
+                println!("validate: {}", describe_validate(&catcher.validate));
+                println!("run: {}", catcher.run);
+            }
+            None => println!("`{}` to `{}` would get a 404", method, path),
+        }
+        return Ok(());
+    }
+
+    let problems = check::problems(&config);
+    for problem in &problems {
+        let severity = match problem.severity {
+            check::Severity::Warning => "warning",
+            check::Severity::Error | check::Severity::Fatal => "error",
+        };
+        match problem.catcher {
+            Some(i) => println!("{}: {}: {}", location(i), severity, problem.message),
+            None => println!(
+                "{}: {}: {}",
+                config.files[0].display(),
+                severity,
+                problem.message
+            ),
+        }
+    }
+    // Warnings alone shouldn't stop a deploy.
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity >= check::Severity::Error)
+        .count();
+    if errors == 0 {
+        Ok(())
+    } else {
+        anyhow::bail!("Found {} error(s)", errors)
+    }
+}
+
+fn describe_validate(validate: &Validate) -> String {
+    match validate {
+        Validate::Dont => "nothing".to_owned(),
+        Validate::Command(cmd) => format!("the command `{}`", cmd),
+        Validate::GitHub(_) => "a GitHub signature".to_owned(),
+        Validate::ClientCert(names) if names.is_empty() => "a client certificate".to_owned(),
+        Validate::ClientCert(names) => {
+            format!("a client certificate for one of {}", names.join(", "))
+        }
+    }
+}
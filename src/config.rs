@@ -1,16 +1,236 @@
 use anyhow::Context;
+use hyper::Method;
+use log::{warn, LevelFilter};
 use serde::{
-    de::{self, Error},
+    de::{self, DeserializeOwned, Error},
     Deserialize,
 };
-use std::{fs, path::PathBuf};
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{check, payload::MethodWrapper};
+
+// Substitutes `${VAR}` and `${VAR:-default}` in `s` with the value of the
+// environment variable `VAR`. `$${` is left as a literal `${`.
+pub fn interpolate(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated `${{` in `{}`", s))?
+            + start;
+        let (var, default) = match rest[start + 2..end].split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (&rest[start + 2..end], None),
+        };
+        match (env::var(var), default) {
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => {
+                return Err(format!(
+                    "Environment variable {} is not set or is not valid UTF-8",
+                    var
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn interpolated<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    interpolate(&s).map_err(D::Error::custom)
+}
+
+fn interpolated_path<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    interpolated(deserializer).map(PathBuf::from)
+}
+
+fn interpolated_option<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| interpolate(&s).map_err(D::Error::custom))
+        .transpose()
+}
 
-use crate::payload::MethodWrapper;
+fn interpolated_path_option<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    interpolated_option(deserializer).map(|s| s.map(PathBuf::from))
+}
+
+fn interpolated_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| interpolate(s).map_err(D::Error::custom))
+        .collect()
+}
+
+fn interpolated_paths<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(interpolated_list(deserializer)?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect())
+}
+
+// The port can also be a string, so that it can come from the environment.
+fn port<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u16),
+        Str(String),
+    }
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(port) => Ok(Some(port)),
+        NumberOrString::Str(s) => {
+            let s = interpolate(&s).map_err(D::Error::custom)?;
+            s.parse()
+                .map(Some)
+                .map_err(|_| D::Error::invalid_value(de::Unexpected::Str(&s), &"a port number"))
+        }
+    }
+}
+
+// An entry of `.listen`.
+#[derive(Clone, PartialEq)]
+pub enum Listen {
+    // `host:port`
+    Tcp(String),
+    Unix(UnixSocket),
+}
+
+#[derive(Clone, PartialEq)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Listen {
+    // How catchers refer to it.
+    pub fn name(&self) -> String {
+        match self {
+            Listen::Tcp(addr) => addr.clone(),
+            Listen::Unix(socket) => format!("unix:{}", socket.path.display()),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Listen {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Unix {
+            unix: String,
+            mode: Option<String>,
+            owner: Option<String>,
+            group: Option<String>,
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AllPossible {
+            Str(String),
+            Unix(Unix),
+        }
+        let interpolate = |s: &str| interpolate(s).map_err(D::Error::custom);
+        match de::Deserialize::deserialize(deserializer)? {
+            AllPossible::Str(s) => {
+                let s = interpolate(&s)?;
+                match s.strip_prefix("unix:") {
+                    Some(path) => Ok(Listen::Unix(UnixSocket {
+                        path: PathBuf::from(path),
+                        mode: None,
+                        owner: None,
+                        group: None,
+                    })),
+                    None => Ok(Listen::Tcp(s)),
+                }
+            }
+            AllPossible::Unix(unix) => {
+                let mode = match unix.mode {
+                    Some(mode) => Some(u32::from_str_radix(&mode, 8).map_err(|_| {
+                        D::Error::invalid_value(
+                            de::Unexpected::Str(&mode),
+                            &"an octal file mode such as \"660\"",
+                        )
+                    })?),
+                    None => None,
+                };
+                Ok(Listen::Unix(UnixSocket {
+                    path: PathBuf::from(interpolate(&unix.unix)?),
+                    mode,
+                    owner: unix.owner.as_deref().map(interpolate).transpose()?,
+                    group: unix.group.as_deref().map(interpolate).transpose()?,
+                }))
+            }
+        }
+    }
+}
 
 pub enum Validate {
     Dont,
     Command(String),
-    GitHub(String),
+    GitHub(Secret),
+    // A TLS client certificate, with one of these names if there are any.
+    ClientCert(Vec<String>),
+}
+
+// A secret key, either given as is (possibly from `${VAR}`), or as `$VAR`,
+// the name of an environment variable to read it from whenever it's needed.
+pub enum Secret {
+    Key(String),
+    Var(String),
+}
+
+impl Secret {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.strip_prefix('$') {
+            Some(var) if !var.starts_with(['{', '$']) => Ok(Secret::Var(var.to_owned())),
+            // What this expands to is the key itself, even if it starts with
+            // a `$`.
+            _ => interpolate(s).map(Secret::Key),
+        }
+    }
 }
 
 impl<'de> de::Deserialize<'de> for Validate {
@@ -23,10 +243,11 @@ impl<'de> de::Deserialize<'de> for Validate {
         enum AllPossible {
             Bool(bool),
             Str(String),
-            Pair([String; 2]),
+            List(Vec<String>),
         }
         let s: AllPossible = de::Deserialize::deserialize(deserializer)?;
         match s {
+            // Like `Catcher.run`, left to the shell.
             AllPossible::Str(s) => Ok(Validate::Command(s)),
             AllPossible::Bool(b) => match b {
                 false => Ok(Validate::Dont),
@@ -35,42 +256,672 @@ impl<'de> de::Deserialize<'de> for Validate {
                     &"the only valid values are `false`, a preset validation method, or a string",
                 )),
             },
-            AllPossible::Pair([method, key]) => match method.as_str() {
-                "github" => Ok(Validate::GitHub(key)),
+            AllPossible::List(list) => match list.split_first() {
+                Some((method, [key])) if method == "github" => Ok(Validate::GitHub(
+                    Secret::parse(key).map_err(D::Error::custom)?,
+                )),
+                Some((method, names)) if method == "mtls" => Ok(Validate::ClientCert(
+                    names
+                        .iter()
+                        .map(|name| interpolate(name).map_err(D::Error::custom))
+                        .collect::<Result<_, _>>()?,
+                )),
                 _ => Err(D::Error::invalid_value(
-                    de::Unexpected::Str(&method),
-                    &"The only preset validation method supported so far is GitHub",
+                    de::Unexpected::Seq,
+                    &"`[\"github\", <secret>]` or `[\"mtls\", <allowed names>...]`",
                 )),
             },
         }
     }
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Respond {
+    // Wait for `run` to exit, then send all of its output at once.
+    #[default]
+    Buffered,
+    // Send the output of `run` as it is produced (chunked transfer encoding).
+    Stream,
+    // Like `Stream`, but each line of output is sent as a server-sent event.
+    Sse,
+}
+
+// A token bucket, which holds up to `burst` requests and refills at `rate`
+// requests a second.
+#[derive(Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    // Defaults to `rate` (rounded up), or 1 if that is less.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub per: RateLimitPer,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> f64 {
+        self.burst
+            .map_or_else(|| self.rate.ceil().max(1.0), f64::from)
+    }
+}
+
+// What gets a bucket of its own.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitPer {
+    // Each remote IP address (requests over Unix sockets all share one).
+    #[default]
+    Ip,
+    // Each catcher, whoever the requests are from.
+    Catcher,
+}
+
+fn default_backoff() -> f64 {
+    1.0
+}
+
+fn default_max_backoff() -> f64 {
+    3600.0
+}
+
+#[derive(Deserialize)]
+pub struct Retry {
+    pub max_attempts: u32,
+    // Seconds to wait before the first retry, doubling on every retry after that.
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: f64,
+    // Exit codes that are worth retrying on; if empty, any failure is.
+    #[serde(default)]
+    pub exit_codes: Vec<i32>,
+}
+
+impl Retry {
+    // Whether a run exiting with `code` (`None` if it could not run or was
+    // killed by a signal) should be retried.
+    pub fn retries(&self, code: Option<i32>) -> bool {
+        match code {
+            Some(code) => self.exit_codes.is_empty() || self.exit_codes.contains(&code),
+            None => self.exit_codes.is_empty(),
+        }
+    }
+
+    // How long to wait after the given (failed) attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let secs = self.backoff * 2f64.powi(attempt as i32 - 1);
+        // Forever, for all intents and purposes, if it's too long for a
+        // `Duration`.
+        Duration::try_from_secs_f64(secs.min(self.max_backoff)).unwrap_or(Duration::MAX)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Catcher {
+    #[serde(deserialize_with = "interpolated")]
     pub path: String,
+    // Not interpolated, since the shell already does that.
     pub run: String,
     pub methods: Vec<MethodWrapper>,
     pub validate: Validate,
+    #[serde(default)]
+    pub response: Respond,
+    // Respond immediately and run in the background.
+    #[serde(default, rename = "async")]
+    pub run_async: bool,
+    #[serde(default)]
+    pub retry: Option<Retry>,
+    // On top of `Config.rate_limit`.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    // Overrides `Config.max_body_size`.
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+    // The entries of `Config.listen` the catcher is restricted to; if empty,
+    // it is on all of them.
+    #[serde(default, deserialize_with = "interpolated_list")]
+    pub listen: Vec<String>,
+    // The index into `Config.files` of the file the catcher is from.
+    #[serde(skip)]
+    pub file: usize,
+}
+
+// What GitHub sends at most.
+fn default_max_body_size() -> u64 {
+    25 * 1024 * 1024
+}
+
+fn default_max_headers() -> usize {
+    100
+}
+
+fn default_max_header_size() -> usize {
+    32 * 1024
+}
+
+fn default_shutdown_timeout() -> f64 {
+    30.0
+}
+
+fn default_max_entries() -> u64 {
+    10000
+}
+
+fn default_history_max_body_size() -> u64 {
+    64 * 1024
+}
+
+// How much of the request history to keep.
+#[derive(Deserialize, Clone)]
+pub struct History {
+    #[serde(default = "default_max_entries")]
+    pub max_entries: u64,
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+    // How much of each request body is kept.
+    #[serde(default = "default_history_max_body_size")]
+    pub max_body_size: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            max_entries: default_max_entries(),
+            max_age_days: None,
+            max_body_size: default_history_max_body_size(),
+        }
+    }
+}
+
+fn default_live_path() -> Option<String> {
+    Some("/healthz".to_owned())
+}
+
+fn default_ready_path() -> Option<String> {
+    Some("/readyz".to_owned())
+}
+
+// The paths of the built-in health checks; `null` turns one off.
+#[derive(Deserialize, Clone)]
+pub struct Health {
+    #[serde(
+        default = "default_live_path",
+        deserialize_with = "interpolated_option"
+    )]
+    pub live: Option<String>,
+    #[serde(
+        default = "default_ready_path",
+        deserialize_with = "interpolated_option"
+    )]
+    pub ready: Option<String>,
+}
+
+impl Health {
+    pub fn is_health_path(&self, path: &str) -> bool {
+        self.live.as_deref() == Some(path) || self.ready.as_deref() == Some(path)
+    }
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+// Logging, besides what is given on the command line.
+#[derive(Deserialize, Clone)]
+pub struct Log {
+    // Overridden by `--log-level`.
+    #[serde(default)]
+    pub level: Option<LevelFilter>,
+    // Rotate `--log-file` once it would grow bigger than this many bytes...
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    // ...or every hour or day.
+    #[serde(default)]
+    pub rotate: Option<RotateEvery>,
+    // How many rotated files to keep.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            level: None,
+            max_size: None,
+            rotate: None,
+            keep: default_keep(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotateEvery {
+    Hourly,
+    Daily,
+}
+
+impl RotateEvery {
+    // The number of the hour or day (in UTC) `time` is in.
+    pub fn period(self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        match self {
+            RotateEvery::Hourly => secs / 3600,
+            RotateEvery::Daily => secs / 86400,
+        }
+    }
+}
+
+// Where to log requests to, and how.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct AccessLog {
+    #[serde(deserialize_with = "interpolated_path")]
+    pub file: PathBuf,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    // Also with the duration and the catcher at the end.
+    #[default]
+    Combined,
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_owned()
+}
+
+// Serving metrics, which list the paths of catchers.
+#[derive(Deserialize, Clone)]
+pub struct Metrics {
+    #[serde(default = "default_metrics_path", deserialize_with = "interpolated")]
+    pub path: String,
+    // The entries of `Config.listen` to serve them on; if empty, all of them.
+    #[serde(default, deserialize_with = "interpolated_list")]
+    pub listen: Vec<String>,
+}
+
+impl Metrics {
+    pub fn is_on(&self, listener: &str) -> bool {
+        self.listen.is_empty() || self.listen.iter().any(|l| l == listener)
+    }
+}
+
+// Serving HTTPS.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Tls {
+    // PEM files with the certificate chain and the private key.
+    #[serde(deserialize_with = "interpolated_path")]
+    pub cert: PathBuf,
+    #[serde(deserialize_with = "interpolated_path")]
+    pub key: PathBuf,
+    // Check the files for changes every so often.
+    #[serde(default)]
+    pub reload: bool,
+    // A PEM file with the CA certificates client certificates must be
+    // signed by, for `["mtls", ...]` validation. Unlike the certificate,
+    // it is only read on startup.
+    #[serde(default, deserialize_with = "interpolated_path_option")]
+    pub client_ca: Option<PathBuf>,
+    // The entries of `Config.listen` to use TLS on; if empty, all TCP ones.
+    #[serde(default, deserialize_with = "interpolated_list")]
+    pub listen: Vec<String>,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub port: u16,
-    pub catchers: Vec<Catcher>,
+    // Shorthand for listening on `0.0.0.0:<port>`.
+    #[serde(default, deserialize_with = "port")]
+    pub port: Option<u16>,
+    // Addresses to listen on.
+    #[serde(default)]
+    pub listen: Vec<Listen>,
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub catchers: Vec<Arc<Catcher>>,
+    // Files, or directories of files, with more catchers.
+    #[serde(default, deserialize_with = "interpolated_paths")]
+    pub include: Vec<PathBuf>,
+    #[serde(default)]
+    pub history: History,
+    // Applies to every catcher.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    // In bytes.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: u64,
+    #[serde(default = "default_max_headers")]
+    pub max_headers: usize,
+    // Of all the names and values together, in bytes.
+    #[serde(default = "default_max_header_size")]
+    pub max_header_size: usize,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub access_log: Option<AccessLog>,
+    // Off unless given.
+    #[serde(default)]
+    pub health: Option<Health>,
+    // Off unless given.
+    #[serde(default)]
+    pub metrics: Option<Metrics>,
+    // Seconds to wait for `.run` commands to finish when shutting down,
+    // before terminating them.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: f64,
+    // The files the configuration was read from, the main one first.
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
 }
 
-const CONFIG_FILE: &str = "config.json";
+impl Config {
+    // Whether webhookee handles GET requests to `path` itself, on any of
+    // `listen` (or on any listener, if it is empty).
+    pub fn is_builtin_path(&self, path: &str, listen: &[String]) -> bool {
+        let health = self
+            .health
+            .as_ref()
+            .is_some_and(|health| health.is_health_path(path));
+        let metrics = self.metrics.as_ref().is_some_and(|metrics| {
+            metrics.path == path && (listen.is_empty() || listen.iter().any(|l| metrics.is_on(l)))
+        });
+        health || metrics
+    }
 
-pub fn get(config_path: Option<PathBuf>) -> anyhow::Result<Config> {
-    let cfg_path;
+    // Everything to listen on, `.port` included.
+    pub fn listeners(&self) -> Vec<Listen> {
+        let mut listeners = Vec::with_capacity(self.listen.len() + 1);
+        if let Some(port) = self.port {
+            listeners.push(Listen::Tcp(format!("0.0.0.0:{}", port)));
+        }
+        listeners.extend(self.listen.iter().cloned());
+        listeners
+    }
+
+    // The catcher that should handle a request, if any, regardless of which
+    // listener it came in on.
+    pub fn find_catcher(&self, method: &Method, path: &str) -> Option<&Arc<Catcher>> {
+        self.catchers
+            .iter()
+            .find(|catcher| catcher.path == path && catcher.methods.iter().any(|m| m == method))
+    }
+
+    // The catcher that should handle a request that came in on `listener`.
+    pub fn find_catcher_on(
+        &self,
+        listener: &str,
+        method: &Method,
+        path: &str,
+    ) -> Option<&Arc<Catcher>> {
+        self.catchers.iter().find(|catcher| {
+            catcher.path == path
+                && catcher.methods.iter().any(|m| m == method)
+                && (catcher.listen.is_empty() || catcher.listen.iter().any(|l| l == listener))
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    // The format of a configuration file with the given extension, if it is
+    // one webhookee knows.
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Some(Format::Json),
+            Some("toml") => Some(Format::Toml),
+            Some("yaml") | Some("yml") => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    // Guesses the format of a configuration file from its extension,
+    // defaulting to JSON.
+    pub fn of(path: &Path) -> Format {
+        Format::from_extension(path).unwrap_or(Format::Json)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Toml => "TOML",
+            Format::Yaml => "YAML",
+        }
+    }
+}
+
+pub struct ParseError {
+    pub format: Format,
+    // The 1-based line and column the error is at, if known.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} did not fit data format: {}",
+            self.format.name(),
+            self.message
+        )
+    }
+}
+
+// The 1-based line and column of a byte offset into `src`.
+pub fn line_column(src: &str, offset: usize) -> (usize, usize) {
+    let line_start = src[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = src[..offset].matches('\n').count() + 1;
+    (line, src[line_start..offset].chars().count() + 1)
+}
+
+// Drops the ` at line X column Y` that serde_json and serde_yaml put at the
+// end of their errors, since `ParseError` keeps the location separately.
+fn without_location(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(i) => message[..i].to_owned(),
+        None => message,
+    }
+}
+
+pub fn parse<T: DeserializeOwned>(src: &str, format: Format) -> Result<T, ParseError> {
+    let (location, message) = match format {
+        Format::Json => match serde_json::from_str(src) {
+            Ok(config) => return Ok(config),
+            Err(e) => (
+                Some((e.line(), e.column())).filter(|&(line, _)| line != 0),
+                without_location(e.to_string()),
+            ),
+        },
+        Format::Toml => match toml::from_str(src) {
+            Ok(config) => return Ok(config),
+            Err(e) => (
+                e.span().map(|span| line_column(src, span.start)),
+                e.message().to_owned(),
+            ),
+        },
+        Format::Yaml => match serde_yaml::from_str(src) {
+            Ok(config) => return Ok(config),
+            Err(e) => (
+                e.location().map(|loc| (loc.line(), loc.column())),
+                without_location(e.to_string()),
+            ),
+        },
+    };
+    Err(ParseError {
+        format,
+        location,
+        message,
+    })
+}
+
+// Reads and parses a configuration file, with any parse error prefixed by
+// where in the file it is.
+fn read_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let src = fs::read_to_string(path)
+        .with_context(|| format!("Could not open configuration file {}", path.display()))?;
+    parse(&src, Format::of(path)).map_err(|e| match e.location {
+        Some((line, column)) => {
+            anyhow::Error::msg(format!("{}:{}:{}: {}", path.display(), line, column, e))
+        }
+        None => anyhow::Error::msg(format!("{}: {}", path.display(), e)),
+    })
+}
+
+// A file that only adds catchers, from `include` or `catchers.d`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fragment {
+    catchers: Vec<Catcher>,
+}
+
+pub const INCLUDE_DIR: &str = "catchers.d";
+
+// The files to read catchers from for an `include` entry: the files with a
+// known extension in it, in name order, if it is a directory, or otherwise
+// just itself.
+fn included_files(include: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !include.is_dir() {
+        return Ok(vec![include.to_owned()]);
+    }
+    let mut files = Vec::new();
+    let entries = fs::read_dir(include)
+        .with_context(|| format!("Could not read directory {}", include.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Could not read directory {}", include.display()))?
+            .path();
+        if path.is_file() && Format::from_extension(&path).is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Reads the configuration file at `cfg_path` along with everything it
+// includes.
+pub fn load(cfg_path: &Path) -> anyhow::Result<Config> {
+    let mut config: Config = read_file(cfg_path)?;
+    config.files.push(cfg_path.to_owned());
+    // Includes are relative to the main configuration file.
+    let cfg_dir = cfg_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut includes: Vec<_> = config
+        .include
+        .iter()
+        .map(|include| cfg_dir.join(include))
+        .collect();
+    let include_dir = cfg_dir.join(INCLUDE_DIR);
+    if include_dir.is_dir() && !includes.contains(&include_dir) {
+        includes.push(include_dir);
+    }
+    for include in includes {
+        for file in included_files(&include)? {
+            if config.files.contains(&file) {
+                continue;
+            }
+            let fragment: Fragment = read_file(&file)?;
+            config.files.push(file);
+            for mut catcher in fragment.catchers {
+                catcher.file = config.files.len() - 1;
+                config.catchers.push(Arc::new(catcher));
+            }
+        }
+    }
+    Ok(config)
+}
+
+const CONFIG_FILE_NAMES: &[&str] = &["config.json", "config.toml", "config.yaml", "config.yml"];
+
+// The path of the configuration file, falling back to whichever of the
+// default ones exists.
+pub fn path(config_path: Option<PathBuf>) -> anyhow::Result<PathBuf> {
     if let Some(config_path) = config_path {
-        cfg_path = config_path;
+        Ok(config_path)
     } else {
         let cfg_dir = dirs::config_dir()
-            .ok_or_else(|| anyhow::Error::msg("Could not get config directory"))?;
-        cfg_path = cfg_dir.join(crate::PROJ_NAME).join(CONFIG_FILE);
+            .ok_or_else(|| anyhow::Error::msg("Could not get config directory"))?
+            .join(crate::PROJ_NAME);
+        Ok(CONFIG_FILE_NAMES
+            .iter()
+            .map(|name| cfg_dir.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| cfg_dir.join(CONFIG_FILE_NAMES[0])))
+    }
+}
+
+// Reads the configuration at `cfg_path`, as long as it is good enough to use.
+fn load_checked(cfg_path: &Path) -> anyhow::Result<Config> {
+    let config = load(cfg_path)?;
+    // Only refuse to start over problems that would make catchers misbehave;
+    // the rest are up to `webhookee check`.
+    if let Some(problem) = check::problems(&config)
+        .into_iter()
+        .find(|p| p.severity == check::Severity::Fatal)
+    {
+        anyhow::bail!("{}", problem.message);
+    }
+    Ok(config)
+}
+
+pub fn get(config_path: Option<PathBuf>) -> anyhow::Result<Config> {
+    load_checked(&path(config_path)?)
+}
+
+// The configuration of a running instance, which can be reloaded. Requests
+// keep using the configuration they started with.
+pub struct Handle {
+    path: PathBuf,
+    current: RwLock<Arc<Config>>,
+    // Whether the last reload failed.
+    reload_failed: AtomicBool,
+}
+
+impl Handle {
+    pub fn open(config_path: Option<PathBuf>) -> anyhow::Result<Handle> {
+        let path = path(config_path)?;
+        let config = load_checked(&path)?;
+        Ok(Handle {
+            path,
+            current: RwLock::new(Arc::new(config)),
+            reload_failed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn reload_failed(&self) -> bool {
+        self.reload_failed.load(Ordering::SeqCst)
+    }
+
+    // Reads the configuration again, and switches to it if it can be used.
+    pub fn reload(&self) -> anyhow::Result<Arc<Config>> {
+        let config = load_checked(&self.path);
+        self.reload_failed.store(config.is_err(), Ordering::SeqCst);
+        let config = Arc::new(config?);
+        let old = std::mem::replace(&mut *self.current.write().unwrap(), config.clone());
+        if old.listeners() != config.listeners()
+            || old.tls != config.tls
+            || old.access_log != config.access_log
+        {
+            warn!(
+                "Changing `port`, `listen`, `tls` or `access_log` only takes effect after a restart"
+            );
+        }
+        Ok(config)
     }
-    let cfg_file = fs::File::open(cfg_path).context("Could not open configuration file")?;
-    serde_json::from_reader(cfg_file).context("JSON did not fit data format")
 }
@@ -0,0 +1,68 @@
+// The built-in health checks, for load balancers and the like.
+
+use crate::State;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Health {
+    // Whether requests can be handled, including async ones.
+    ready: bool,
+    config: ConfigHealth,
+    queue: QueueHealth,
+}
+
+#[derive(Serialize)]
+struct ConfigHealth {
+    catchers: usize,
+    // Whether the configuration could not be reloaded the last time, in
+    // which case the old one is still in use. Why is only logged, since
+    // anyone can see this.
+    reload_failed: bool,
+}
+
+#[derive(Serialize)]
+struct QueueHealth {
+    // The number of async jobs waiting or running, if it can be found out.
+    depth: Option<usize>,
+    writable: bool,
+}
+
+// Responds to a request for one of the `.health` paths. The liveness check
+// always succeeds if it gets a response at all, while the readiness check
+// fails if async jobs can't be queued.
+pub async fn respond(state: &'static State, request: &Request<Body>) -> Option<Response<Body>> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return None;
+    }
+    let config = state.config.get();
+    let health = config.health.as_ref()?;
+    let path = Some(request.uri().path());
+    let live = health.live.as_deref() == path;
+    if !live && health.ready.as_deref() != path {
+        return None;
+    }
+
+    let depth = state.jobs.queue().count().ok();
+    let writable = state.jobs.queue().writable().await;
+    let health = Health {
+        ready: depth.is_some() && writable,
+        config: ConfigHealth {
+            catchers: config.catchers.len(),
+            reload_failed: state.config.reload_failed(),
+        },
+        queue: QueueHealth { depth, writable },
+    };
+    let mut response = Response::new(Body::from(
+        serde_json::to_vec(&health).expect("Could not serialize health"),
+    ));
+    if !live && !health.ready {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Some(response)
+}
@@ -0,0 +1,307 @@
+// A record of every request to a catcher, kept in a SQLite database.
+
+use crate::{
+    config,
+    payload::{BytesOrString, HeaderMapWrapper, Payload},
+    queue,
+};
+
+use anyhow::Context;
+use hyper::header::HeaderValue;
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use std::{
+    convert::TryFrom,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+// How much of the output of `.run` is kept.
+pub const OUTPUT_LIMIT: usize = 4096;
+
+// How often old entries are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Headers that are kept out of the history, since they carry secrets or
+// signatures made with them.
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-hub-signature",
+    "x-hub-signature-256",
+    "x-gitlab-token",
+];
+
+pub enum Validation {
+    Passed,
+    Failed,
+    Error,
+    // The request was replayed without validating it.
+    Skipped,
+}
+
+impl Validation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Validation::Passed => "passed",
+            Validation::Failed => "failed",
+            Validation::Error => "error",
+            Validation::Skipped => "skipped",
+        }
+    }
+}
+
+// How a run of `.run` ended.
+pub struct Outcome<'a> {
+    pub attempts: u32,
+    pub exit_code: Option<i32>,
+    // e.g. `exit status: 1`, or why the command could not be run at all.
+    pub status: String,
+    pub output: &'a [u8],
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub id: String,
+    pub received_at: u64,
+    pub finished_at: Option<u64>,
+    pub validation: String,
+    pub attempts: Option<u32>,
+    pub exit_code: Option<i32>,
+    pub status: Option<String>,
+    pub output: Option<BytesOrString>,
+    pub payload: Payload,
+    // Whether only the start of the body was kept.
+    pub body_truncated: bool,
+}
+
+impl Entry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let payload_str: String = row.get("payload")?;
+        let output: Option<Vec<u8>> = row.get("output")?;
+        Ok(Entry {
+            id: row.get("id")?,
+            received_at: row.get("received_at")?,
+            finished_at: row.get("finished_at")?,
+            validation: row.get("validation")?,
+            attempts: row.get("attempts")?,
+            exit_code: row.get("exit_code")?,
+            status: row.get("status")?,
+            output: output.map(BytesOrString::from),
+            payload: serde_json::from_str(&payload_str).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+            })?,
+            body_truncated: row.get("body_truncated")?,
+        })
+    }
+}
+
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    retention: config::History,
+}
+
+impl History {
+    pub fn open(data_dir: &Path, retention: config::History) -> anyhow::Result<Self> {
+        let db_path = data_dir.join("history.sqlite3");
+        let conn = Connection::open(&db_path)
+            .with_context(|| format!("Could not open history database {}", db_path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS invocations (
+                id TEXT PRIMARY KEY,
+                received_at INTEGER NOT NULL,
+                finished_at INTEGER,
+                validation TEXT NOT NULL,
+                attempts INTEGER,
+                exit_code INTEGER,
+                status TEXT,
+                output BLOB,
+                payload TEXT NOT NULL,
+                body_truncated INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS invocations_received_at ON invocations (received_at);",
+        )
+        .context("Could not set up history database")?;
+        // Databases from before bodies were truncated don't have the column.
+        if conn
+            .prepare("SELECT body_truncated FROM invocations")
+            .is_err()
+        {
+            conn.execute_batch(
+                "ALTER TABLE invocations ADD COLUMN body_truncated INTEGER NOT NULL DEFAULT 0",
+            )
+            .context("Could not set up history database")?;
+        }
+        Ok(History {
+            conn: Arc::new(Mutex::new(conn)),
+            retention,
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        lock(&self.conn)
+    }
+
+    // Runs `f` on a thread that may block, so that waiting for SQLite (or for
+    // another request to be done with it) doesn't hold up anything else.
+    async fn with_conn<F>(&self, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<usize> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&lock(&conn)))
+            .await
+            .context("History database task failed")??;
+        Ok(())
+    }
+
+    // Records a request that was routed to a catcher. Errors are only logged,
+    // since the history should never get in the way of handling requests.
+    pub async fn record_request(&self, id: &str, req_payload: &Payload, validation: Validation) {
+        let (stored, body_truncated) = self.stored(req_payload);
+        let res = match serde_json::to_string(&stored)
+            .context("Could not serialize request payload")
+        {
+            Ok(payload_str) => {
+                let id = id.to_owned();
+                self.with_conn(move |conn| {
+                    conn.execute(
+                        "INSERT INTO invocations (id, received_at, validation, payload, body_truncated)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![id, queue::now(), validation.as_str(), payload_str, body_truncated],
+                    )
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            error!("Could not record request {} in history: {:#}", id, e);
+        }
+    }
+
+    // The payload as it is kept: without secrets, and with no more of the
+    // body than `.history.max_body_size`.
+    fn stored(&self, req_payload: &Payload) -> (Payload, bool) {
+        let mut headers = req_payload.headers.0.clone();
+        for (name, value) in headers.iter_mut() {
+            if REDACTED_HEADERS.contains(&name.as_str()) {
+                *value = HeaderValue::from_static("[redacted]");
+            }
+        }
+        let max = usize::try_from(self.retention.max_body_size).unwrap_or(usize::MAX);
+        let body_truncated = req_payload
+            .body
+            .as_ref()
+            .is_some_and(|body| body.as_bytes().len() > max);
+        let body = req_payload.body.as_ref().map(|body| match body {
+            BytesOrString::Str(s) => {
+                let mut end = s.len().min(max);
+                while !s.is_char_boundary(end) {
+                    end -= 1;
+                }
+                BytesOrString::Str(s[..end].to_owned())
+            }
+            BytesOrString::Bytes(b) => BytesOrString::Bytes(b[..b.len().min(max)].to_vec()),
+        });
+        let stored = Payload {
+            method: req_payload.method.clone(),
+            path: req_payload.path.clone(),
+            headers: HeaderMapWrapper(headers),
+            body,
+            client_cert: req_payload.client_cert.clone(),
+        };
+        (stored, body_truncated)
+    }
+
+    // Records how the run of `.run` for a request ended.
+    pub async fn record_outcome(&self, id: &str, outcome: Outcome<'_>) {
+        let output = outcome.output[..outcome.output.len().min(OUTPUT_LIMIT)].to_vec();
+        let Outcome {
+            attempts,
+            exit_code,
+            status,
+            ..
+        } = outcome;
+        let owned_id = id.to_owned();
+        let res = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "UPDATE invocations
+                    SET finished_at = ?2, attempts = ?3, exit_code = ?4, status = ?5, output = ?6
+                    WHERE id = ?1",
+                    params![owned_id, queue::now(), attempts, exit_code, status, output],
+                )
+            })
+            .await;
+        if let Err(e) = res {
+            error!("Could not record outcome of {} in history: {:#}", id, e);
+        }
+    }
+
+    async fn prune(&self) -> anyhow::Result<()> {
+        if let Some(max_age_days) = self.retention.max_age_days {
+            let cutoff = queue::now().saturating_sub(max_age_days.saturating_mul(24 * 60 * 60));
+            self.with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM invocations WHERE received_at < ?1",
+                    params![cutoff],
+                )
+            })
+            .await?;
+        }
+        let max_entries = self.retention.max_entries;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM invocations WHERE id NOT IN
+                (SELECT id FROM invocations ORDER BY received_at DESC LIMIT ?1)",
+                params![max_entries],
+            )
+        })
+        .await
+    }
+
+    // The most recent entries, newest first.
+    pub fn recent(&self, limit: u64) -> anyhow::Result<Vec<Entry>> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT * FROM invocations ORDER BY received_at DESC, rowid DESC LIMIT ?1")?;
+        let entries = stmt
+            .query_map(params![limit], Entry::from_row)?
+            .collect::<rusqlite::Result<_>>()
+            .context("Could not read history")?;
+        Ok(entries)
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<Entry>> {
+        self.conn()
+            .query_row(
+                "SELECT * FROM invocations WHERE id = ?1",
+                params![id],
+                Entry::from_row,
+            )
+            .optional()
+            .context("Could not read history")
+    }
+}
+
+fn lock(conn: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    // A panic while holding the lock can't leave SQLite in a bad state.
+    conn.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Forgets old entries every so often, rather than on every request.
+pub fn spawn_pruning(history: Arc<History>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = history.prune().await {
+                error!("Could not forget old history: {:#}", e);
+            }
+        }
+    });
+}
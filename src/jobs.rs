@@ -0,0 +1,301 @@
+// Catchers that run in the background (`"async": true`).
+
+use crate::{
+    config::{Catcher, Config},
+    history::{History, Outcome},
+    logging,
+    payload::Payload,
+    queue::{self, DeadJob, Failure, Job, JobDir},
+    run::{self, Children},
+};
+
+use anyhow::Context;
+use log::{error, info, warn};
+use std::{
+    path::Path,
+    process::{Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+pub struct Jobs {
+    queue: JobDir<Job>,
+    dead_letters: JobDir<DeadJob>,
+    history: Arc<History>,
+    children: Arc<Children>,
+    // How many jobs are being run in the background, so that shutting down
+    // can wait for them to be done with the queue.
+    running: AtomicUsize,
+}
+
+// Counts towards `Jobs::running` until dropped.
+struct Running<'a>(&'a AtomicUsize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Jobs {
+    pub fn open(
+        data_dir: &Path,
+        history: Arc<History>,
+        children: Arc<Children>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Jobs {
+            queue: JobDir::open(data_dir.join("queue"))?,
+            dead_letters: JobDir::open(data_dir.join("dead-letter"))?,
+            history,
+            children,
+            running: AtomicUsize::new(0),
+        })
+    }
+
+    fn track(&self) -> Running<'_> {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        Running(&self.running)
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Waits for the jobs being run in the background to finish, or to leave
+    // themselves in the queue if webhookee is shutting down, for at most
+    // `timeout`. Returns whether they did.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.running() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    pub fn queue(&self) -> &JobDir<Job> {
+        &self.queue
+    }
+
+    pub fn dead_letters(&self) -> &JobDir<DeadJob> {
+        &self.dead_letters
+    }
+
+    // Queues the job, then runs it in the background.
+    pub async fn submit(
+        &'static self,
+        catcher: Arc<Catcher>,
+        job: Job,
+    ) -> Result<JoinHandle<bool>, anyhow::Error> {
+        self.queue.save(&job.id, &job).await?;
+        let running = self.track();
+        Ok(logging::spawn(async move {
+            let _running = running;
+            self.run(&catcher, job).await
+        }))
+    }
+
+    // Restarts the jobs left in the queue from a previous run.
+    pub fn resume(&'static self, config: &Config) -> Result<(), anyhow::Error> {
+        let mut jobs = self.queue.all()?;
+        jobs.sort_by_key(|job| job.queued_at);
+        for job in jobs {
+            match config.find_catcher(&job.payload.method.0, &job.payload.path) {
+                Some(catcher) if catcher.run_async => {
+                    info!(
+                        "Resuming `{}` to `{}` after {} attempt(s)",
+                        job.payload.method.0, job.payload.path, job.attempts
+                    );
+                    let catcher = catcher.clone();
+                    let request = logging::Request::new(
+                        job.id.clone(),
+                        &job.payload.method.0,
+                        &job.payload.path,
+                        None,
+                    );
+                    let running = self.track();
+                    tokio::spawn(logging::scope(request, async move {
+                        let _running = running;
+                        logging::set_catcher(&catcher.path);
+                        self.run(&catcher, job).await
+                    }));
+                }
+                _ => error!(
+                    "No async catcher for queued `{}` to `{}` anymore, leaving job {} in the queue",
+                    job.payload.method.0, job.payload.path, job.id
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a queued job until it succeeds or runs out of attempts, in which
+    // case it is moved to the dead letters. Returns whether it succeeded.
+    // If webhookee is shutting down, it is left in the queue to be resumed
+    // on the next start instead.
+    pub async fn run(&self, catcher: &Catcher, mut job: Job) -> bool {
+        let max_attempts = catcher.retry.as_ref().map_or(1, |r| r.max_attempts);
+        let mut succeeded = false;
+        while job.attempts < max_attempts {
+            if self.children.stopping() {
+                return self.leave_queued(&job);
+            }
+            let attempt = job.attempts + 1;
+            let req_payload = &job.payload;
+            let started_at = queue::now();
+            let failure = match run_once(&self.children, catcher, req_payload, attempt).await {
+                Ok(output) if output.status.success() => {
+                    info!(
+                        "`{}` to `{}` finished (attempt {}/{})",
+                        req_payload.method.0, req_payload.path, attempt, max_attempts
+                    );
+                    self.history
+                        .record_outcome(
+                            &job.id,
+                            Outcome {
+                                attempts: attempt,
+                                exit_code: output.status.code(),
+                                status: output.status.to_string(),
+                                output: &output.stdout,
+                            },
+                        )
+                        .await;
+                    succeeded = true;
+                    break;
+                }
+                // It was most likely terminated for taking too long to
+                // finish.
+                Ok(_) if self.children.stopping() => return self.leave_queued(&job),
+                Ok(output) => {
+                    error!(
+                        "`{}` to `{}` failed with {} (attempt {}/{})",
+                        req_payload.method.0,
+                        req_payload.path,
+                        output.status,
+                        attempt,
+                        max_attempts
+                    );
+                    Failure {
+                        started_at,
+                        failed_at: queue::now(),
+                        status: output.status.to_string(),
+                        exit_code: output.status.code(),
+                        stdout: output.stdout.into(),
+                        stderr: output.stderr.into(),
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "`{}` to `{}` failed (attempt {}/{}): {:#}",
+                        req_payload.method.0, req_payload.path, attempt, max_attempts, e
+                    );
+                    Failure {
+                        started_at,
+                        failed_at: queue::now(),
+                        status: format!("{:#}", e),
+                        exit_code: None,
+                        stdout: Vec::new().into(),
+                        stderr: Vec::new().into(),
+                    }
+                }
+            };
+            job.attempts = attempt;
+            let retry = catcher
+                .retry
+                .as_ref()
+                .filter(|retry| attempt < max_attempts && retry.retries(failure.exit_code));
+            job.last_failure = Some(failure);
+            let retry = match retry {
+                Some(retry) => retry,
+                None => break,
+            };
+            if let Err(e) = self.queue.save(&job.id, &job).await {
+                error!("{:#}", e);
+            }
+            let delay = retry.delay(attempt);
+            warn!(
+                "Retrying `{}` to `{}` in {:?}",
+                job.payload.method.0, job.payload.path, delay
+            );
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = self.children.stopped() => return self.leave_queued(&job),
+            }
+        }
+        let id = job.id.clone();
+        if !succeeded {
+            // It can have run out of attempts before it was resumed, if
+            // `max_attempts` has been lowered since or webhookee crashed
+            // right after the last one.
+            let last_failure = job.last_failure.take().unwrap_or_else(|| Failure {
+                started_at: queue::now(),
+                failed_at: queue::now(),
+                status: "Out of attempts".to_owned(),
+                exit_code: None,
+                stdout: Vec::new().into(),
+                stderr: Vec::new().into(),
+            });
+            error!(
+                "Giving up on `{}` to `{}`, moving it to dead letter {}",
+                job.payload.method.0, job.payload.path, id
+            );
+            self.history
+                .record_outcome(
+                    &id,
+                    Outcome {
+                        attempts: job.attempts,
+                        exit_code: last_failure.exit_code,
+                        status: last_failure.status.clone(),
+                        output: last_failure.stdout.as_bytes(),
+                    },
+                )
+                .await;
+            let dead_job = DeadJob { job, last_failure };
+            if let Err(e) = self.dead_letters.save(&id, &dead_job).await {
+                error!("{:#}", e);
+            }
+        }
+        if let Err(e) = self.queue.remove(&id).await {
+            error!("{:#}", e);
+        }
+        succeeded
+    }
+
+    fn leave_queued(&self, job: &Job) -> bool {
+        warn!(
+            "Shutting down, leaving `{}` to `{}` in the queue until the next start",
+            job.payload.method.0, job.payload.path
+        );
+        false
+    }
+
+    // Takes a job out of the dead letters and runs it again from scratch.
+    pub async fn retry_dead(&self, catcher: &Catcher, id: &str) -> Result<bool, anyhow::Error> {
+        let mut job = self.dead_letters.get(id)?.job;
+        job.attempts = 0;
+        self.queue.save(&job.id, &job).await?;
+        self.dead_letters.remove(id).await?;
+        Ok(self.run(catcher, job).await)
+    }
+}
+
+async fn run_once(
+    children: &Arc<Children>,
+    catcher: &Catcher,
+    req_payload: &Payload,
+    attempt: u32,
+) -> Result<Output, anyhow::Error> {
+    let run = run::spawn(children, catcher, req_payload, attempt, Stdio::piped()).await?;
+    run.wait_with_output()
+        .await
+        .context("Could not wait for process to finish")
+}
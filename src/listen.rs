@@ -0,0 +1,279 @@
+// Binding the addresses in `.listen`.
+
+use crate::config::{Listen, UnixSocket};
+use crate::payload::ClientCert;
+use crate::queue;
+
+use anyhow::Context;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use log::{error, info};
+use std::{
+    ffi::{CString, OsString},
+    fmt, fs,
+    future::Future,
+    io, mem,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+    path::PathBuf,
+    pin::Pin,
+    ptr,
+    task::{ready, Poll},
+    time::Duration,
+};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::Sleep;
+
+pub enum Incoming {
+    Tcp(AddrIncoming),
+    Unix(UnixListener),
+}
+
+pub struct Listener {
+    // The name of the `.listen` entry it is for.
+    pub name: &'static str,
+    pub incoming: Incoming,
+    // The socket file webhookee created, if it did.
+    pub socket_file: Option<SocketFile>,
+}
+
+// A Unix socket file, which is removed when this is dropped, unless something
+// else has replaced it in the meantime.
+pub struct SocketFile {
+    path: PathBuf,
+    ino: u64,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if fs::symlink_metadata(&self.path).is_ok_and(|meta| meta.ino() == self.ino) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Could not remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// Accepts connections on a Unix socket.
+pub fn accept_unix(
+    listener: UnixListener,
+) -> impl Accept<Conn = UnixStream, Error = io::Error> + Unpin {
+    retry_errors(accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }))
+}
+
+// Passes on the connections from `incoming`. An error would make hyper stop
+// serving the listener altogether, so it is logged and retried instead, and
+// never passed on.
+pub fn retry_errors<I>(mut incoming: I) -> impl Accept<Conn = I::Conn, Error = io::Error> + Unpin
+where
+    I: Accept + Unpin,
+    I::Error: fmt::Display,
+{
+    let mut backoff: Option<Pin<Box<Sleep>>> = None;
+    accept::poll_fn(move |cx| loop {
+        if let Some(sleep) = &mut backoff {
+            ready!(sleep.as_mut().poll(cx));
+            backoff = None;
+        }
+        match ready!(Pin::new(&mut incoming).poll_accept(cx)) {
+            Some(Ok(conn)) => return Poll::Ready(Some(Ok(conn))),
+            Some(Err(e)) => {
+                // Probably out of file descriptors, which takes a while to
+                // get better.
+                error!("Could not accept connection: {}", e);
+                backoff = Some(Box::pin(tokio::time::sleep(Duration::from_millis(100))));
+            }
+            None => return Poll::Ready(None),
+        }
+    })
+}
+
+// What is known about the other end of a connection.
+pub trait Peer {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        None
+    }
+}
+
+impl Peer for AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl Peer for UnixStream {}
+
+// A connection requests are coming in on.
+pub struct Connection {
+    // The name of the listener it was accepted on.
+    pub listener: &'static str,
+    pub remote_addr: Option<SocketAddr>,
+    pub client_cert: Option<ClientCert>,
+}
+
+impl Connection {
+    pub fn new(listener: &'static str, peer: &impl Peer) -> Self {
+        Connection {
+            listener,
+            remote_addr: peer.remote_addr(),
+            client_cert: peer.client_cert(),
+        }
+    }
+}
+
+// Binds every entry. A `host:port` entry is bound on every address it
+// resolves to; `localhost:8000`, for example, may be both `127.0.0.1:8000`
+// and `[::1]:8000`.
+pub async fn bind(entries: Vec<Listen>) -> Result<Vec<Listener>, anyhow::Error> {
+    let mut listeners = Vec::new();
+    for entry in entries {
+        let name: &'static str = Box::leak(entry.name().into_boxed_str());
+        match entry {
+            Listen::Tcp(entry) => {
+                let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host(entry.as_str())
+                    .await
+                    .with_context(|| format!("Could not resolve `{}`", entry))?
+                    .collect();
+                addrs.dedup();
+                for addr in addrs {
+                    let incoming = AddrIncoming::bind(&addr)
+                        .with_context(|| format!("Could not listen on {}", addr))?;
+                    info!("Listening on {}", addr);
+                    listeners.push(Listener {
+                        name,
+                        incoming: Incoming::Tcp(incoming),
+                        socket_file: None,
+                    });
+                }
+            }
+            Listen::Unix(socket) => {
+                let (incoming, socket_file) =
+                    bind_unix(&socket).with_context(|| format!("Could not listen on {}", name))?;
+                info!("Listening on {}", name);
+                listeners.push(Listener {
+                    name,
+                    incoming: Incoming::Unix(incoming),
+                    socket_file: Some(socket_file),
+                });
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind_unix(socket: &UnixSocket) -> Result<(UnixListener, SocketFile), anyhow::Error> {
+    // A socket left over from a previous run would make binding fail.
+    if let Ok(meta) = fs::symlink_metadata(&socket.path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", socket.path.display());
+        }
+        fs::remove_file(&socket.path).context("Could not remove old socket")?;
+    }
+    let listener = if socket.mode.is_none() && socket.owner.is_none() && socket.group.is_none() {
+        UnixListener::bind(&socket.path)?
+    } else {
+        bind_unix_privately(socket)?
+    };
+    let ino = fs::symlink_metadata(&socket.path)
+        .context("Could not find socket")?
+        .ino();
+    Ok((
+        listener,
+        SocketFile {
+            path: socket.path.clone(),
+            ino,
+        },
+    ))
+}
+
+// Binds the socket in a directory only webhookee can get into, and only moves
+// it into place once it has the right owner and mode, so that nobody else can
+// connect in the meantime.
+fn bind_unix_privately(socket: &UnixSocket) -> Result<UnixListener, anyhow::Error> {
+    let mut dir_name = OsString::from(".");
+    dir_name.push(socket.path.file_name().context("Invalid socket path")?);
+    dir_name.push(format!(".{}", queue::new_id()));
+    let dir = socket.path.with_file_name(dir_name);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Could not create {}", dir.display()))?;
+    let tmp_path = dir.join("socket");
+    let bound = (|| {
+        let listener = UnixListener::bind(&tmp_path)?;
+        if socket.owner.is_some() || socket.group.is_some() {
+            let uid = socket.owner.as_deref().map(user_id).transpose()?;
+            let gid = socket.group.as_deref().map(group_id).transpose()?;
+            std::os::unix::fs::chown(&tmp_path, uid, gid).context("Could not change owner")?;
+        }
+        if let Some(mode) = socket.mode {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))
+                .context("Could not change mode")?;
+        }
+        fs::rename(&tmp_path, &socket.path).context("Could not move socket into place")?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_file(&tmp_path);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+// Calls a `get*nam_r` function with a big enough buffer. Returns whether an
+// entry was found.
+fn lookup<T>(
+    entry: &mut T,
+    f: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+) -> io::Result<bool> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut result = ptr::null_mut();
+        match f(entry, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 => return Ok(!result.is_null()),
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+// Looks up a user by name, or takes it as a numeric ID if there is none.
+fn user_id(user: &str) -> Result<u32, anyhow::Error> {
+    let name = CString::new(user).context("Invalid user name")?;
+    // SAFETY: all zeroes is a valid `passwd`, only its (non-pointer) ID is
+    // used once the buffer is gone.
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    // SAFETY: `name` is a valid C string, and the buffer is as big as it says.
+    let found = lookup(&mut passwd, |passwd, buf, len, result| unsafe {
+        libc::getpwnam_r(name.as_ptr(), passwd, buf, len, result)
+    });
+    match found {
+        Ok(true) => Ok(passwd.pw_uid),
+        _ => user
+            .parse()
+            .map_err(|_| anyhow::Error::msg(format!("No user `{}`", user))),
+    }
+}
+
+// Looks up a group by name, or takes it as a numeric ID if there is none.
+fn group_id(group: &str) -> Result<u32, anyhow::Error> {
+    let name = CString::new(group).context("Invalid group name")?;
+    // SAFETY: as for `passwd` in `user_id`.
+    let mut group_entry: libc::group = unsafe { mem::zeroed() };
+    // SAFETY: as for `getpwnam_r` in `user_id`.
+    let found = lookup(&mut group_entry, |group_entry, buf, len, result| unsafe {
+        libc::getgrnam_r(name.as_ptr(), group_entry, buf, len, result)
+    });
+    match found {
+        Ok(true) => Ok(group_entry.gr_gid),
+        _ => group
+            .parse()
+            .map_err(|_| anyhow::Error::msg(format!("No group `{}`", group))),
+    }
+}
@@ -0,0 +1,416 @@
+// Setting up logging: where to, how much, rotating the log file, and logging
+// as JSON, one object per line, with the details of the request being handled
+// on every line logged while handling it.
+
+use crate::{access_log, config};
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use log::{error, info, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+
+#[derive(Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format `{}`", s)),
+        }
+    }
+}
+
+// What was given on the command line.
+struct Settings {
+    // `--log-level`, which takes precedence over `.log.level`.
+    level: Option<LevelFilter>,
+    file: Option<LogFile>,
+    json: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// Sets up logging to `file`, or to standard output (standard error for
+// subcommands, which have their own output).
+pub fn init(
+    format: &Format,
+    level: Option<LevelFilter>,
+    file: Option<&Path>,
+    stderr: bool,
+) -> Result<(), anyhow::Error> {
+    use simplelog::{ColorChoice, Config, TermLogger, TerminalMode, WriteLogger};
+
+    let file = file.map(LogFile::open).transpose()?;
+    // The loggers let everything through, it's `log::max_level` that filters,
+    // so that it can be changed later.
+    match (format, &file) {
+        (Format::Json, Some(file)) => JsonLogger::init(Box::new(file.clone())),
+        (Format::Json, None) if stderr => JsonLogger::init(Box::new(io::stderr())),
+        (Format::Json, None) => JsonLogger::init(Box::new(io::stdout())),
+        (Format::Text, Some(file)) => {
+            WriteLogger::init(LevelFilter::Trace, Config::default(), file.clone())
+        }
+        (Format::Text, None) => TermLogger::init(
+            LevelFilter::Trace,
+            Config::default(),
+            if stderr {
+                TerminalMode::Stderr
+            } else {
+                TerminalMode::Stdout
+            },
+            ColorChoice::Auto,
+        ),
+    }
+    .expect("Could not initialize logging");
+    log::set_max_level(level.unwrap_or(LevelFilter::Info));
+    let _ = SETTINGS.set(Settings {
+        level,
+        file,
+        json: matches!(format, Format::Json),
+    });
+    Ok(())
+}
+
+// Applies `.log` from the configuration.
+pub fn configure(log: &config::Log) {
+    if let Some(settings) = SETTINGS.get() {
+        log::set_max_level(settings.level.or(log.level).unwrap_or(LevelFilter::Info));
+        if let Some(file) = &settings.file {
+            file.0.lock().unwrap().rotation = log.clone();
+        }
+    }
+}
+
+// Reopens the log files on SIGUSR1, for when something else has moved them.
+pub fn spawn_reopen() -> Result<(), anyhow::Error> {
+    let mut usr1 = signal(SignalKind::user_defined1()).context("Could not listen for SIGUSR1")?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            if let Some(file) = SETTINGS.get().and_then(|settings| settings.file.as_ref()) {
+                match file.reopen() {
+                    Ok(()) => info!("Got SIGUSR1, reopened log file"),
+                    Err(e) => error!("Could not reopen log file: {}", e),
+                }
+            }
+            if let Err(e) = access_log::reopen() {
+                error!("Could not reopen access log: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// A log file, which is appended to. `--log-file` is also rotated as `.log`
+// says.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<OpenLogFile>>);
+
+struct OpenLogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // When it was last written to.
+    written: SystemTime,
+    // Whether the last write ended a line, since a line may be written in
+    // several parts and shouldn't be split between files.
+    line_ended: bool,
+    rotation: config::Log,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = open_append(path)
+            .with_context(|| format!("Could not open log file {}", path.display()))?;
+        let meta = file.metadata().context("Could not open log file")?;
+        Ok(LogFile(Arc::new(Mutex::new(OpenLogFile {
+            path: path.to_owned(),
+            file,
+            size: meta.len(),
+            written: meta.modified().unwrap_or_else(|_| SystemTime::now()),
+            line_ended: true,
+            rotation: config::Log::default(),
+        }))))
+    }
+
+    pub fn reopen(&self) -> io::Result<()> {
+        self.0.lock().unwrap().reopen()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl OpenLogFile {
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = file;
+        Ok(())
+    }
+
+    fn rotation_due(&self, len: usize, now: SystemTime) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + len as u64 > max_size);
+        let next_period = self
+            .rotation
+            .rotate
+            .is_some_and(|every| every.period(self.written) != every.period(now));
+        self.line_ended && (too_big || next_period)
+    }
+
+    // Moves `<file>.<n>` to `<file>.<n + 1>`, dropping the ones after
+    // `.log.keep`, and starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = OsString::from(&self.path);
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..self.rotation.keep).rev() {
+            match fs::rename(numbered(n), numbered(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.rotation.keep > 0 {
+            fs::rename(&self.path, numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.reopen()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        if log.rotation_due(buf.len(), now) {
+            // This can't be logged, and it's better to keep logging to the
+            // same file than to stop.
+            if let Err(e) = log.rotate() {
+                eprintln!("Could not rotate log file: {}", e);
+            }
+        }
+        let written = log.file.write(buf)?;
+        log.size += written as u64;
+        log.written = now;
+        log.line_ended = buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+
+tokio::task_local! {
+    static REQUEST: Arc<Request>;
+}
+
+pub struct Request {
+    // Also the ID of the request in the history.
+    pub id: String,
+    method: String,
+    path: String,
+    remote_addr: Option<SocketAddr>,
+    // What is found out while handling it.
+    details: Mutex<Details>,
+}
+
+#[derive(Default)]
+struct Details {
+    catcher: Option<String>,
+    validation: Option<&'static str>,
+    status: Option<u16>,
+    duration: Option<Duration>,
+}
+
+impl Request {
+    pub fn new(
+        id: String,
+        method: &hyper::Method,
+        path: &str,
+        remote_addr: Option<SocketAddr>,
+    ) -> Arc<Self> {
+        Arc::new(Request {
+            id,
+            method: method.to_string(),
+            path: path.to_owned(),
+            remote_addr,
+            details: Mutex::default(),
+        })
+    }
+
+    // A copy for work that goes on after the response has been sent, which
+    // shouldn't have its status and duration.
+    fn detached(&self) -> Arc<Self> {
+        let details = self.details.lock().unwrap();
+        Arc::new(Request {
+            id: self.id.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            remote_addr: self.remote_addr,
+            details: Mutex::new(Details {
+                catcher: details.catcher.clone(),
+                validation: details.validation,
+                ..Details::default()
+            }),
+        })
+    }
+
+    // The path of the catcher handling it, once found.
+    pub fn catcher(&self) -> Option<String> {
+        self.details.lock().unwrap().catcher.clone()
+    }
+
+    fn add_fields(&self, fields: &mut Map<String, Value>) {
+        fields.insert("request_id".to_owned(), self.id.clone().into());
+        fields.insert("method".to_owned(), self.method.clone().into());
+        fields.insert("path".to_owned(), self.path.clone().into());
+        if let Some(remote_addr) = self.remote_addr {
+            fields.insert("remote_addr".to_owned(), remote_addr.to_string().into());
+        }
+        let details = self.details.lock().unwrap();
+        if let Some(catcher) = &details.catcher {
+            fields.insert("catcher".to_owned(), catcher.clone().into());
+        }
+        if let Some(validation) = details.validation {
+            fields.insert("validation".to_owned(), validation.into());
+        }
+        if let Some(status) = details.status {
+            fields.insert("status".to_owned(), status.into());
+        }
+        if let Some(duration) = details.duration {
+            fields.insert(
+                "duration_ms".to_owned(),
+                (duration.as_millis() as u64).into(),
+            );
+        }
+    }
+}
+
+// Runs `future` as part of handling `request`.
+pub async fn scope<F: Future>(request: Arc<Request>, future: F) -> F::Output {
+    REQUEST.scope(request, future).await
+}
+
+// Like `tokio::spawn`, but the task is still part of handling the current
+// request, if there is one.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match REQUEST.try_with(|request| request.detached()) {
+        Ok(request) => tokio::spawn(REQUEST.scope(request, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+fn with_details(f: impl FnOnce(&mut Details)) {
+    let _ = REQUEST.try_with(|request| f(&mut request.details.lock().unwrap()));
+}
+
+pub fn set_catcher(path: &str) {
+    with_details(|details| details.catcher = Some(path.to_owned()));
+}
+
+pub fn set_validation(validation: &'static str) {
+    with_details(|details| details.validation = Some(validation));
+}
+
+// Logs that the current request has been responded to, for JSON logs only,
+// where the line has its details. The access log is there for text.
+pub fn finish(status: hyper::StatusCode, duration: Duration) {
+    if !SETTINGS.get().is_some_and(|settings| settings.json) {
+        return;
+    }
+    with_details(|details| {
+        details.status = Some(status.as_u16());
+        details.duration = Some(duration);
+    });
+    let _ = REQUEST.try_with(|request| {
+        let from = request
+            .remote_addr
+            .map_or_else(String::new, |addr| format!(" from {}", addr));
+        info!(
+            "`{}` to `{}`{} answered with {} in {} ms",
+            request.method,
+            request.path,
+            from,
+            status,
+            duration.as_millis()
+        );
+    });
+}
+
+struct JsonLogger {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLogger {
+    fn init(out: Box<dyn Write + Send>) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(JsonLogger {
+            out: Mutex::new(out),
+        }))
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = Map::new();
+        fields.insert(
+            "time".to_owned(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        fields.insert("level".to_owned(), record.level().as_str().into());
+        fields.insert("target".to_owned(), record.target().into());
+        fields.insert("message".to_owned(), record.args().to_string().into());
+        let _ = REQUEST.try_with(|request| request.add_fields(&mut fields));
+
+        let mut line = serde_json::to_vec(&fields).expect("Could not serialize log line");
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        // There's nowhere to report it if logging fails.
+        let _ = out.write_all(&line).and_then(|_| out.flush());
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
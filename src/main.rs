@@ -1,68 +1,183 @@
+mod access_log;
+mod check;
+mod cli;
 mod config;
+mod health;
+mod history;
+mod jobs;
+mod listen;
+mod logging;
+mod metrics;
 mod payload;
+mod queue;
+mod rate_limit;
+mod reload;
+mod run;
+mod systemd;
+mod tls;
 mod validate;
 
 use anyhow::Context;
 use config::Catcher;
-use log::{error, info};
+use history::{Outcome, Validation};
+use listen::Peer;
+use log::{error, info, warn};
 
+use hyper::body::{self, Bytes};
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use hyper::server::accept::Accept;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio::process::ChildStdout;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 
 pub const PROJ_NAME: &str = "webhookee";
 
+struct State {
+    config: config::Handle,
+    history: Arc<history::History>,
+    jobs: jobs::Jobs,
+    children: Arc<run::Children>,
+    rate_limits: rate_limit::Limiter,
+}
+
+impl State {
+    fn new(config: config::Handle, data_dir: &Path) -> Result<Self, anyhow::Error> {
+        let history = Arc::new(history::History::open(
+            data_dir,
+            config.get().history.clone(),
+        )?);
+        let children = Arc::new(run::Children::default());
+        Ok(State {
+            config,
+            jobs: jobs::Jobs::open(data_dir, history.clone(), children.clone())?,
+            history,
+            children,
+            rate_limits: rate_limit::Limiter::default(),
+        })
+    }
+}
+
 enum CatcherReturn {
     // The webhook was allowed.
-    Allowed(Vec<u8> /* the body */),
+    Allowed(Body),
+    // The webhook was allowed and is running in the background. The handle
+    // resolves to whether it succeeded.
+    Accepted(JoinHandle<bool>),
     // It was denied
     Denied,
 }
 
 async fn invoke_catcher(
-    catcher: &Catcher,
+    state: &'static State,
+    catcher: Arc<Catcher>,
+    id: String,
     request: Request<Body>,
+    client_cert: Option<payload::ClientCert>,
+    max_body_size: u64,
 ) -> Result<CatcherReturn, anyhow::Error> {
-    let req_payload = payload::decode_payload(request)
+    let req_payload = payload::decode_payload(request, client_cert, max_body_size)
         .await
         .context("Could not decode payload")?;
+    catch_payload(state, catcher, id, req_payload, true).await
+}
+
+// Handles a request once its payload has been decoded, skipping validation
+// if `validate` is false.
+async fn catch_payload(
+    state: &'static State,
+    catcher: Arc<Catcher>,
+    id: String,
+    req_payload: payload::Payload,
+    validate: bool,
+) -> Result<CatcherReturn, anyhow::Error> {
     // First validate the request.
-    if validate::validate(catcher, &req_payload)
-        .await
-        .context("Could not validate request")?
-    {
-        let mut run = Command::new("/bin/sh")
-            .arg("-c")
-            .arg(&catcher.run)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .context("Could not execute .run command")?;
-        {
-            let mut stdin = run
-                .stdin
-                .take()
-                .context("Could not get stdin handle of run")?;
-            let payload_str = serde_json::to_string(&req_payload)
-                .context("Could not serialize request payload")?;
-            stdin
-                .write_all(payload_str.as_bytes())
+    let validation = if !validate {
+        Validation::Skipped
+    } else {
+        match validate::validate(&catcher, &req_payload).await {
+            Ok(true) => Validation::Passed,
+            Ok(false) => Validation::Failed,
+            Err(e) => {
+                metrics::validation(&catcher.path, "error");
+                logging::set_validation(Validation::Error.as_str());
+                state
+                    .history
+                    .record_request(&id, &req_payload, Validation::Error)
+                    .await;
+                return Err(e.context("Could not validate request"));
+            }
+        }
+    };
+    match validation {
+        Validation::Passed => metrics::validation(&catcher.path, "passed"),
+        Validation::Failed => metrics::validation(&catcher.path, "failed"),
+        Validation::Error | Validation::Skipped => {}
+    }
+    logging::set_validation(validation.as_str());
+    let valid = !matches!(validation, Validation::Failed);
+    state
+        .history
+        .record_request(&id, &req_payload, validation)
+        .await;
+    if valid {
+        if catcher.run_async {
+            let job = state
+                .jobs
+                .submit(catcher, queue::Job::new(id, req_payload))
                 .await
-                .context("Could not write request payload to run command")?;
-            // Close standard input
-            drop(stdin);
+                .context("Could not queue job")?;
+            return Ok(CatcherReturn::Accepted(job));
+        }
+        let mut run =
+            run::spawn(&state.children, &catcher, &req_payload, 1, Stdio::inherit()).await?;
+        match catcher.response {
+            config::Respond::Buffered => {
+                let output = run
+                    .wait_with_output()
+                    .await
+                    .context("Could not wait for process to finish")?;
+                state
+                    .history
+                    .record_outcome(
+                        &id,
+                        Outcome {
+                            attempts: 1,
+                            exit_code: output.status.code(),
+                            status: output.status.to_string(),
+                            output: &output.stdout,
+                        },
+                    )
+                    .await;
+                Ok(CatcherReturn::Allowed(Body::from(output.stdout)))
+            }
+            config::Respond::Stream | config::Respond::Sse => {
+                let stdout = run
+                    .stdout
+                    .take()
+                    .context("Could not get stdout handle of run")?;
+                let (sender, body) = Body::channel();
+                logging::spawn(stream_output(
+                    state,
+                    id,
+                    run,
+                    stdout,
+                    sender,
+                    catcher.response == config::Respond::Sse,
+                ));
+                Ok(CatcherReturn::Allowed(body))
+            }
         }
-        let output = run
-            .wait_with_output()
-            .await
-            .context("Could not wait for process to finish")?;
-        Ok(CatcherReturn::Allowed(output.stdout))
     } else {
         info!(
             "`{}` to `{}` failed validation, ignoring",
@@ -72,94 +187,467 @@ async fn invoke_catcher(
     }
 }
 
+// Forwards the output of `run` to the response body as it is produced.
+async fn stream_output(
+    state: &'static State,
+    id: String,
+    mut run: run::Child,
+    stdout: ChildStdout,
+    mut sender: body::Sender,
+    sse: bool,
+) {
+    // If the client goes away we keep reading anyway, so that `run` never
+    // blocks on a full pipe.
+    let mut client_gone = false;
+    // The start of the output, for the history.
+    let mut kept = Vec::new();
+    let mut stdout = BufReader::new(stdout);
+    loop {
+        let mut buf = Vec::new();
+        let read = if sse {
+            stdout.read_until(b'\n', &mut buf).await
+        } else {
+            buf.resize(8192, 0);
+            stdout.read(&mut buf).await
+        };
+        match read {
+            Ok(0) => break,
+            Ok(n) => buf.truncate(n),
+            Err(e) => {
+                error!("Could not read output of .run command: {}", e);
+                break;
+            }
+        }
+        kept.extend_from_slice(&buf[..buf.len().min(history::OUTPUT_LIMIT - kept.len())]);
+        let chunk = if sse {
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+            }
+            Bytes::from(format!("data: {}\n\n", String::from_utf8_lossy(&buf)))
+        } else {
+            Bytes::from(buf)
+        };
+        if !client_gone {
+            client_gone = sender.send_data(chunk).await.is_err();
+        }
+    }
+    match run.wait().await {
+        Ok(status) => {
+            state
+                .history
+                .record_outcome(
+                    &id,
+                    Outcome {
+                        attempts: 1,
+                        exit_code: status.code(),
+                        status: status.to_string(),
+                        output: &kept,
+                    },
+                )
+                .await
+        }
+        Err(e) => error!("Could not wait for process to finish: {}", e),
+    }
+}
+
 async fn handle_request(
-    config: &config::Config,
+    state: &'static State,
+    conn: Arc<listen::Connection>,
     request: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
+    let access = access_log::start(&conn, &request);
+    let (response, catcher) = if let Some(response) = health::respond(state, &request).await {
+        (response, None)
+    } else if let Some(response) = metrics::respond(state, conn.listener, &request) {
+        (response, None)
+    } else {
+        let started = Instant::now();
+        let id = queue::new_id();
+        let log_request = logging::Request::new(
+            id.clone(),
+            request.method(),
+            request.uri().path(),
+            conn.remote_addr,
+        );
+        let response = logging::scope(log_request.clone(), async move {
+            let response = respond(state, &conn, id, request).await;
+            logging::finish(response.status(), started.elapsed());
+            response
+        })
+        .await;
+        (response, log_request.catcher())
+    };
+    Ok(match access {
+        Some(access) => access.finish(response, catcher),
+        None => response,
+    })
+}
+
+async fn respond(
+    state: &'static State,
+    conn: &listen::Connection,
+    id: String,
+    request: Request<Body>,
+) -> Response<Body> {
     let mut response = Response::new(Body::empty());
-    match config.catchers.iter().find(|&catcher| {
-        catcher.path == request.uri().path()
-            && catcher.methods.iter().any(|m| m == request.method())
-    }) {
-        Some(catcher) => match invoke_catcher(catcher, request).await {
+    let config = state.config.get();
+    // Hyper has already read them, within `max_buf_size`, but they can still
+    // be too many or too big for what is configured now.
+    let headers = request.headers();
+    let request_line_size = request.method().as_str().len()
+        + request
+            .uri()
+            .path_and_query()
+            .map_or(0, |path| path.as_str().len());
+    let header_size: usize = request_line_size
+        + headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+    if headers.len() > config.max_headers || header_size > config.max_header_size {
+        info!(
+            "`{}` to `{}` has too many or too large headers, returning 431",
+            request.method(),
+            request.uri().path()
+        );
+        *response.status_mut() = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        return response;
+    }
+    let catcher = config
+        .find_catcher_on(conn.listener, request.method(), request.uri().path())
+        .cloned();
+    if let Some(catcher) = &catcher {
+        metrics::request(&catcher.path);
+        logging::set_catcher(&catcher.path);
+        // Before reading the body, let alone validating it.
+        let ip = conn.remote_addr.map(|addr| addr.ip());
+        if let Err(wait) = state.rate_limits.check(&config, catcher, ip) {
+            metrics::rate_limited(&catcher.path);
+            info!(
+                "`{}` to `{}` is over its rate limit, returning 429",
+                request.method(),
+                catcher.path
+            );
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            // Rounded up, so that retrying then succeeds.
+            let retry_after = wait
+                .as_secs()
+                .saturating_add(u64::from(wait.subsec_nanos() > 0));
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+            return response;
+        }
+    }
+    match catcher {
+        Some(catcher) => match invoke_catcher(
+            state,
+            catcher.clone(),
+            id,
+            request,
+            conn.client_cert.clone(),
+            catcher.max_body_size.unwrap_or(config.max_body_size),
+        )
+        .await
+        {
             Ok(ret) => match ret {
                 CatcherReturn::Denied => {
                     // Deny the request.
                     *response.status_mut() = StatusCode::FORBIDDEN;
-                    Ok(response)
+                    response
                 }
-                CatcherReturn::Allowed(body_bytes) => {
+                CatcherReturn::Allowed(body) => {
                     // Return the body.
-                    *response.body_mut() = Body::from(body_bytes);
+                    *response.body_mut() = body;
                     *response.status_mut() = StatusCode::OK;
-                    Ok(response)
+                    if catcher.response == config::Respond::Sse {
+                        response
+                            .headers_mut()
+                            .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+                    }
+                    response
+                }
+                CatcherReturn::Accepted(_) => {
+                    *response.status_mut() = StatusCode::ACCEPTED;
+                    response
                 }
             },
+            Err(e) if e.is::<payload::TooLarge>() => {
+                info!(
+                    "Body of request to `{}` is over `max_body_size`, returning 413",
+                    catcher.path
+                );
+                *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                response
+            }
             Err(e) => {
                 error!("{}", e);
                 *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                Ok(response)
+                response
             }
         },
         None => {
             // Probably just a random HTTP request, ignore it.
+            metrics::not_found();
             info!(
                 "Invalid request {} to path {} found, returning 404",
                 request.method(),
                 request.uri().path()
             );
             *response.status_mut() = StatusCode::NOT_FOUND;
-            Ok(response)
+            response
+        }
+    }
+}
+
+// How long `.run` commands get to exit once they have been asked to when
+// shutting down, before they are killed.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How much of a request hyper reads before it gives up on finding the end of
+// the headers and responds with 431, which is what actually bounds the memory
+// they take up. There's room for `.max_headers` headers adding up to
+// `.max_header_size`, with the separators in between.
+fn max_buf_size(config: &config::Config) -> usize {
+    // Hyper won't go below this.
+    const MIN_MAX_BUF_SIZE: usize = 8192;
+    config
+        .max_header_size
+        .saturating_add(config.max_headers.saturating_mul(4))
+        .saturating_add(64)
+        .max(MIN_MAX_BUF_SIZE)
+}
+
+// Serves requests from one listener in the background, until `stop` changes.
+// Requests that are already being handled are finished first.
+fn serve<I>(
+    state: &'static State,
+    listener: &'static str,
+    incoming: I,
+    mut stop: watch::Receiver<bool>,
+) -> JoinHandle<Result<(), hyper::Error>>
+where
+    I: Accept + Send + 'static,
+    I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let conn = Arc::new(listen::Connection::new(listener, conn));
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(state, conn.clone(), req)
+            }))
         }
+    });
+    tokio::spawn(
+        Server::builder(incoming)
+            .http1_max_buf_size(max_buf_size(&state.config.get()))
+            .serve(make_svc)
+            .with_graceful_shutdown(async move {
+                let _ = stop.changed().await;
+            }),
+    )
+}
+
+// Like `serve`, but over TLS if `tls` is given.
+fn serve_maybe_tls<I>(
+    state: &'static State,
+    listener: &'static str,
+    incoming: I,
+    tls: Option<TlsAcceptor>,
+    stop: watch::Receiver<bool>,
+) -> JoinHandle<Result<(), hyper::Error>>
+where
+    I: Accept + Unpin + Send + 'static,
+    I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>> + std::fmt::Display + Send,
+{
+    match tls {
+        Some(acceptor) => serve(state, listener, tls::accept(incoming, acceptor), stop),
+        None => serve(state, listener, incoming, stop),
     }
 }
 
 #[derive(StructOpt, Debug)]
 struct Opt {
     /// Path to config file (defaults to $XDG_CONFIG_HOME/.config/webhookee/config.json)
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     config: Option<PathBuf>,
     /// Path to log file (defaults to standard output)
     #[structopt(short, long)]
     log_file: Option<PathBuf>,
+    /// Log as plain text, or as one JSON object per line
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    log_format: logging::Format,
+    /// Only log messages at least this important: off, error, warn, info, debug or trace (defaults to `.log.level`, or info)
+    #[structopt(long)]
+    log_level: Option<log::LevelFilter>,
+    /// Reload the configuration whenever it changes, as well as on SIGHUP
+    #[structopt(long)]
+    watch_config: bool,
+    /// Path to the directory to keep state in (defaults to $XDG_DATA_HOME/webhookee)
+    #[structopt(long, global = true)]
+    data_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<cli::Command>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Opt::from_args();
 
-    {
-        use simplelog::*;
-        if let Some(log_file_path) = opt.log_file {
-            let log_file =
-                std::fs::File::create(log_file_path).context("Could not open log file")?;
-            WriteLogger::init(LevelFilter::Info, Config::default(), log_file)
-                .expect("Could not initialize logging");
-        } else {
-            TermLogger::init(
-                LevelFilter::Info,
-                Config::default(),
-                TerminalMode::Stdout,
-                ColorChoice::Auto,
-            )
-            .expect("Could not initialize logging");
-        }
+    logging::init(
+        &opt.log_format,
+        opt.log_level,
+        opt.log_file.as_deref(),
+        opt.cmd.is_some(),
+    )?;
+
+    let data_dir = match opt.data_dir {
+        Some(data_dir) => data_dir,
+        None => dirs::data_dir()
+            .ok_or_else(|| anyhow::Error::msg("Could not get data directory"))?
+            .join(PROJ_NAME),
+    };
+
+    std::fs::create_dir_all(&data_dir)
+        .with_context(|| format!("Could not create data directory {}", data_dir.display()))?;
+
+    if let Some(cmd) = opt.cmd {
+        return cli::run(cmd, opt.config, data_dir).await;
     }
 
-    let config: &'static config::Config = Box::leak(Box::new(config::get(opt.config)?));
+    let state: &'static State = Box::leak(Box::new(State::new(
+        config::Handle::open(opt.config)?,
+        &data_dir,
+    )?));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    logging::configure(&state.config.get().log);
+    access_log::open(state.config.get().access_log.as_ref())?;
+    logging::spawn_reopen()?;
+    state.jobs.resume(&state.config.get())?;
 
-    let make_svc = make_service_fn(|_conn| {
-        let cfg = config;
-        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(cfg, req))) }
-    });
+    // The acceptor, and the listeners to use it on.
+    let (tls, certs) = match &state.config.get().tls {
+        Some(tls_config) => {
+            let (acceptor, certs) = tls::acceptor(tls_config)?;
+            (Some((acceptor, tls_config.listen.clone())), Some(certs))
+        }
+        None => (None, None),
+    };
 
-    let server = Server::bind(&addr).serve(make_svc);
+    reload::spawn(state, opt.watch_config, certs)?;
+    history::spawn_pruning(state.history.clone());
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    let mut listeners = systemd::listeners()?;
+    listeners.extend(listen::bind(state.config.get().listeners()).await?);
+    if listeners.is_empty() {
+        anyhow::bail!("Nothing to listen on, `.port` or `.listen` must be given");
     }
+    // Removed once webhookee has stopped.
+    let _socket_files: Vec<_> = listeners
+        .iter_mut()
+        .filter_map(|listener| listener.socket_file.take())
+        .collect();
+
+    let (stop, stopped) = watch::channel(false);
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let name = listener.name;
+            // TLS is on every TCP listener unless `.tls.listen` says otherwise.
+            let use_tls = |is_tcp: bool| {
+                tls.as_ref()
+                    .filter(|(_, names)| {
+                        (is_tcp && names.is_empty()) || names.iter().any(|n| n == name)
+                    })
+                    .map(|(acceptor, _)| acceptor.clone())
+            };
+            match listener.incoming {
+                listen::Incoming::Tcp(incoming) => {
+                    serve_maybe_tls(state, name, incoming, use_tls(true), stopped.clone())
+                }
+                listen::Incoming::Unix(unix) => serve_maybe_tls(
+                    state,
+                    name,
+                    listen::accept_unix(unix),
+                    use_tls(false),
+                    stopped.clone(),
+                ),
+            }
+        })
+        .collect();
 
+    // Before telling systemd it's ready, so that stopping right away still
+    // drains.
+    let mut terminate = signal(SignalKind::terminate()).context("Could not listen for SIGTERM")?;
+    let mut interrupt = signal(SignalKind::interrupt()).context("Could not listen for SIGINT")?;
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+
+    let servers = async {
+        for server in servers {
+            if let Err(e) = server.await.context("Server panicked")? {
+                eprintln!("server error: {}", e);
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::pin!(servers);
+    tokio::select! {
+        result = &mut servers => return result,
+        _ = terminate.recv() => info!("Got SIGTERM, stopping"),
+        _ = interrupt.recv() => info!("Interrupted, stopping"),
+    }
+    systemd::notify("STOPPING=1");
+    shut_down(state, stop, servers).await
+}
+
+// Stops accepting requests, and waits for the ones being handled, any `.run`
+// commands and the async jobs they belong to to finish. Commands still
+// running after `.shutdown_timeout` are terminated, and their jobs left in
+// the queue.
+async fn shut_down(
+    state: &'static State,
+    stop: watch::Sender<bool>,
+    servers: impl std::future::Future<Output = Result<(), anyhow::Error>>,
+) -> Result<(), anyhow::Error> {
+    let _ = stop.send(true);
+    state.children.stop();
+    let timeout = Duration::from_secs_f64(state.config.get().shutdown_timeout);
+    let running = state.children.count();
+    if running > 0 {
+        info!(
+            "Waiting up to {:?} for {} command(s) to finish",
+            timeout, running
+        );
+    }
+    let drained = async {
+        servers.await?;
+        state.children.wait(timeout).await;
+        state.jobs.wait(timeout).await;
+        Ok::<_, anyhow::Error>(())
+    };
+    if let Ok(result) = tokio::time::timeout(timeout, drained).await {
+        result?;
+    }
+    if state.children.count() > 0 {
+        warn!(
+            "Terminating {} command(s) that did not finish in time",
+            state.children.count()
+        );
+        state.children.kill(libc::SIGTERM);
+        if !state.children.wait(TERMINATE_TIMEOUT).await {
+            state.children.kill(libc::SIGKILL);
+        }
+    }
+    // Jobs whose commands were just terminated still have to be done with
+    // the queue, the rest are waiting to be retried and stop right away.
+    if !state.jobs.wait(TERMINATE_TIMEOUT).await {
+        warn!(
+            "Gave up on waiting for {} job(s), they will be resumed on the next start",
+            state.jobs.running()
+        );
+    }
     Ok(())
 }
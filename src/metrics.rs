@@ -0,0 +1,142 @@
+// Prometheus metrics.
+
+use crate::State;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response};
+use log::error;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::{process::ExitStatus, sync::LazyLock, time::Duration};
+
+// `.run` commands can take anywhere from milliseconds to an hour.
+const RUN_DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "webhookee_requests_total",
+        "Requests to each catcher",
+        &["catcher"]
+    )
+    .unwrap()
+});
+
+static VALIDATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "webhookee_validations_total",
+        "Requests validated by each catcher, by result (passed, failed or error)",
+        &["catcher", "result"]
+    )
+    .unwrap()
+});
+
+static RUNS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "webhookee_runs_total",
+        "Finished `.run` commands of each catcher, by exit code (or `signal`)",
+        &["catcher", "exit_code"]
+    )
+    .unwrap()
+});
+
+static RUN_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "webhookee_run_duration_seconds",
+        "How long `.run` commands of each catcher took",
+        &["catcher"],
+        RUN_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "webhookee_rate_limited_total",
+        "Requests to each catcher turned away for going over a rate limit",
+        &["catcher"]
+    )
+    .unwrap()
+});
+
+static NOT_FOUND: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "webhookee_not_found_total",
+        "Requests that no catcher handles"
+    )
+    .unwrap()
+});
+
+static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("webhookee_queue_depth", "Async jobs that haven't finished").unwrap()
+});
+
+static RUNNING: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("webhookee_running_commands", "`.run` commands running").unwrap()
+});
+
+pub fn request(catcher: &str) {
+    REQUESTS.with_label_values(&[catcher]).inc();
+}
+
+pub fn validation(catcher: &str, result: &str) {
+    VALIDATIONS.with_label_values(&[catcher, result]).inc();
+}
+
+pub fn run(catcher: &str, status: ExitStatus, duration: Duration) {
+    let exit_code = status
+        .code()
+        .map_or_else(|| "signal".to_owned(), |code| code.to_string());
+    RUNS.with_label_values(&[catcher, &exit_code]).inc();
+    RUN_DURATION
+        .with_label_values(&[catcher])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn rate_limited(catcher: &str) {
+    RATE_LIMITED.with_label_values(&[catcher]).inc();
+}
+
+pub fn not_found() {
+    NOT_FOUND.inc();
+}
+
+// Responds to a request for `.metrics.path` that came in on `listener`.
+pub fn respond(
+    state: &'static State,
+    listener: &str,
+    request: &Request<Body>,
+) -> Option<Response<Body>> {
+    let config = state.config.get();
+    let method = request.method();
+    let wanted = config
+        .metrics
+        .as_ref()
+        .is_some_and(|metrics| metrics.path == request.uri().path() && metrics.is_on(listener));
+    if (method != Method::GET && method != Method::HEAD) || !wanted {
+        return None;
+    }
+
+    match state.jobs.queue().count() {
+        Ok(depth) => QUEUE_DEPTH.set(depth as i64),
+        Err(e) => error!("{:#}", e),
+    }
+    RUNNING.set(state.children.count() as i64);
+    // Make sure the ones that haven't been used yet show up too.
+    LazyLock::force(&NOT_FOUND);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        error!("Could not encode metrics: {}", e);
+    }
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(encoder.format_type()).unwrap(),
+    );
+    Some(response)
+}
@@ -1,14 +1,19 @@
 // The request payload.
 
-use std::{borrow::Cow, str::FromStr};
+use std::{borrow::Cow, fmt, io, str::FromStr};
 
 use anyhow::Context;
-use hyper::{Body, HeaderMap, Method, Request};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderName, HeaderValue},
+    Body, HeaderMap, Method, Request,
+};
 use serde::{
     de::{self, Error},
     ser::SerializeMap,
     Deserialize, Serialize,
 };
+use tokio::{io::AsyncWriteExt, process::ChildStdin};
 
 pub struct HeaderMapWrapper(pub HeaderMap);
 impl From<HeaderMap> for HeaderMapWrapper {
@@ -33,6 +38,56 @@ impl Serialize for HeaderMapWrapper {
     }
 }
 
+// Repeated headers show up as repeated keys, which a map type would only keep
+// one of, so every entry is added as it comes. A list of `[name, value]`
+// pairs is taken as well.
+impl<'de> Deserialize<'de> for HeaderMapWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct HeadersVisitor;
+
+        fn append<E: de::Error>(
+            headers: &mut HeaderMap,
+            name: String,
+            value: BytesOrString,
+        ) -> Result<(), E> {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).map_err(E::custom)?,
+                HeaderValue::from_bytes(value.as_bytes()).map_err(E::custom)?,
+            );
+            Ok(())
+        }
+
+        impl<'de> de::Visitor<'de> for HeadersVisitor {
+            type Value = HeaderMap;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object or a list of pairs of header names and values")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<HeaderMap, A::Error> {
+                let mut headers = HeaderMap::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some((name, value)) = map.next_entry()? {
+                    append(&mut headers, name, value)?;
+                }
+                Ok(headers)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<HeaderMap, A::Error> {
+                let mut headers = HeaderMap::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some((name, value)) = seq.next_element()? {
+                    append(&mut headers, name, value)?;
+                }
+                Ok(headers)
+            }
+        }
+
+        deserializer.deserialize_any(HeadersVisitor).map(Self)
+    }
+}
+
 #[derive(Eq, Clone)]
 pub struct MethodWrapper(pub Method);
 impl From<Method> for MethodWrapper {
@@ -71,42 +126,110 @@ impl<'de> Deserialize<'de> for MethodWrapper {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum BytesOrString {
     Bytes(Vec<u8>),
     Str(String),
 }
+impl From<Vec<u8>> for BytesOrString {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(s) => BytesOrString::Str(s),
+            Err(e) => BytesOrString::Bytes(e.into_bytes()),
+        }
+    }
+}
 impl BytesOrString {
     pub fn as_bytes(&self) -> &[u8] {
         match self {
-            BytesOrString::Bytes(b) => &b,
+            BytesOrString::Bytes(b) => b,
             BytesOrString::Str(s) => s.as_bytes(),
         }
     }
 }
 
-#[derive(Serialize)]
+// The certificate a client authenticated with over TLS.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientCert {
+    // The distinguished name, such as `CN=deployer, O=Example`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    // Subject alternative names: DNS names, email addresses, URIs and IP
+    // addresses.
+    pub san: Vec<String>,
+    // The SHA-256 of the DER-encoded certificate, in hex.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    // Whether `name` is the subject, common name or one of the subject
+    // alternative names.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.subject == name
+            || self.common_name.as_deref() == Some(name)
+            || self.san.iter().any(|san| san == name)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Payload {
     pub method: MethodWrapper,
     pub path: String,
     pub headers: HeaderMapWrapper,
     pub body: Option<BytesOrString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<ClientCert>,
 }
 
-pub async fn decode_payload(request: Request<Body>) -> Result<Payload, anyhow::Error> {
-    let (req_info, body) = request.into_parts();
-    let body_slice: &[u8] = &hyper::body::to_bytes(body)
-        .await
-        .context("Could not get HTTP body")?;
-    let body_vec = body_slice.to_vec();
+// The request body was bigger than `max_body_size`.
+#[derive(Debug)]
+pub struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request body is too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+// Fails with `TooLarge` as soon as the body turns out to be more than
+// `max_body_size` bytes, without reading the rest.
+pub async fn decode_payload(
+    request: Request<Body>,
+    client_cert: Option<ClientCert>,
+    max_body_size: u64,
+) -> Result<Payload, anyhow::Error> {
+    let (req_info, mut body) = request.into_parts();
+    // Going by `Content-Length`, if there is one.
+    if body.size_hint().lower() > max_body_size {
+        return Err(TooLarge.into());
+    }
+    let mut body_vec = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.context("Could not get HTTP body")?;
+        if (body_vec.len() + chunk.len()) as u64 > max_body_size {
+            return Err(TooLarge.into());
+        }
+        body_vec.extend_from_slice(&chunk);
+    }
     Ok(Payload {
         method: req_info.method.into(),
         path: req_info.uri.path().to_owned(),
         headers: req_info.headers.into(),
-        body: Some(match String::from_utf8(body_vec) {
-            Ok(s) => BytesOrString::Str(s),
-            Err(e) => BytesOrString::Bytes(e.into_bytes()),
-        }),
+        body: Some(body_vec.into()),
+        client_cert,
     })
 }
+
+// Writes the payload to the standard input of a process, then closes it.
+pub async fn write_to(mut stdin: ChildStdin, payload: &Payload) -> Result<(), anyhow::Error> {
+    let payload_str =
+        serde_json::to_string(payload).context("Could not serialize request payload")?;
+    match stdin.write_all(payload_str.as_bytes()).await {
+        // The process exited without reading all of its input, which is fine.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        res => res.context("Could not write request payload"),
+    }
+}
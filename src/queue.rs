@@ -0,0 +1,181 @@
+// Async jobs that have not finished yet and jobs that failed for good, kept
+// on disk so that they survive restarts.
+
+use crate::payload::{BytesOrString, Payload};
+
+use anyhow::Context;
+use log::error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    fs,
+    marker::PhantomData,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    // Seconds since the Unix epoch.
+    pub queued_at: u64,
+    // How many attempts have finished so far.
+    pub attempts: u32,
+    pub payload: Payload,
+    // How the last of them failed, if any did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<Failure>,
+}
+
+impl Job {
+    pub fn new(id: String, payload: Payload) -> Self {
+        Job {
+            id,
+            queued_at: now(),
+            attempts: 0,
+            payload,
+            last_failure: None,
+        }
+    }
+}
+
+// How an attempt at running a job failed.
+#[derive(Serialize, Deserialize)]
+pub struct Failure {
+    pub started_at: u64,
+    pub failed_at: u64,
+    // e.g. `exit status: 1`, or why the command could not be run at all.
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub stdout: BytesOrString,
+    pub stderr: BytesOrString,
+}
+
+// A job that will not be retried anymore.
+#[derive(Serialize, Deserialize)]
+pub struct DeadJob {
+    pub job: Job,
+    pub last_failure: Failure,
+}
+
+// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// A random identifier, 16 hex characters long.
+pub fn new_id() -> String {
+    use ring::rand::{SecureRandom, SystemRandom};
+    let mut bytes = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Could not generate random bytes");
+    hex::encode(bytes)
+}
+
+// A directory of JSON files, one per job.
+pub struct JobDir<T> {
+    dir: PathBuf,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> JobDir<T> {
+    pub fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create directory {}", dir.display()))?;
+        Ok(JobDir {
+            dir,
+            _marker: PhantomData,
+        })
+    }
+
+    fn job_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id).with_extension("json")
+    }
+
+    // Adds or updates a job.
+    pub async fn save(&self, id: &str, job: &T) -> anyhow::Result<()> {
+        let job_str = serde_json::to_vec(job).context("Could not serialize job")?;
+        // Write to a temporary file first, so that a crash never leaves a
+        // half-written job behind. It has to be on disk before it replaces
+        // the old one, and the rename has to be on disk before the job counts
+        // as saved.
+        let tmp_path = self.job_path(id).with_extension("tmp");
+        let write = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            file.write_all(&job_str).await?;
+            file.sync_all().await
+        };
+        write
+            .await
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, self.job_path(id))
+            .await
+            .with_context(|| format!("Could not write job {}", id))?;
+        let sync_dir = async { tokio::fs::File::open(&self.dir).await?.sync_all().await };
+        sync_dir
+            .await
+            .with_context(|| format!("Could not sync directory {}", self.dir.display()))
+    }
+
+    pub async fn remove(&self, id: &str) -> anyhow::Result<()> {
+        tokio::fs::remove_file(self.job_path(id))
+            .await
+            .with_context(|| format!("Could not remove job {}", id))
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<T> {
+        let job_str = fs::read(self.job_path(id)).with_context(|| format!("No job {}", id))?;
+        serde_json::from_slice(&job_str).context("JSON did not fit data format")
+    }
+
+    // The number of jobs, without reading them.
+    pub fn count(&self) -> anyhow::Result<usize> {
+        let mut count = 0;
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Could not read directory {}", self.dir.display()))?;
+        for entry in entries {
+            let path = entry.context("Could not read directory entry")?.path();
+            if path.extension() == Some(OsStr::new("json")) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Whether jobs can be saved, found out by writing a file that isn't one,
+    // named so that checks at the same time don't get in each other's way.
+    pub async fn writable(&self) -> bool {
+        let probe_path = self
+            .job_path(&format!(".probe-{}", new_id()))
+            .with_extension("tmp");
+        tokio::fs::write(&probe_path, b"").await.is_ok()
+            && tokio::fs::remove_file(&probe_path).await.is_ok()
+    }
+
+    pub fn all(&self) -> anyhow::Result<Vec<T>> {
+        let mut jobs = Vec::new();
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Could not read directory {}", self.dir.display()))?;
+        for entry in entries {
+            let path = entry.context("Could not read directory entry")?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let job = fs::read(&path)
+                .context("Could not read file")
+                .and_then(|job_str| {
+                    serde_json::from_slice(&job_str).context("JSON did not fit data format")
+                });
+            match job {
+                Ok(job) => jobs.push(job),
+                // Don't let one broken job hold up all the others.
+                Err(e) => error!("Skipping job {}: {:#}", path.display(), e),
+            }
+        }
+        Ok(jobs)
+    }
+}
@@ -0,0 +1,150 @@
+// Rate limiting requests to catchers with token buckets, as `.rate_limit` and
+// the `rate_limit` of catchers say.
+
+use crate::config::{Catcher, Config, RateLimit, RateLimitPer};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// The most buckets to keep, after which the least recently used ones are
+// dropped.
+const MAX_BUCKETS: usize = 65536;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    // Whether it's for `.rate_limit` rather than the catcher's own.
+    global: bool,
+    catcher: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+impl Key {
+    fn new(global: bool, limit: &RateLimit, catcher: &Catcher, ip: Option<IpAddr>) -> Self {
+        let per_ip = limit.per == RateLimitPer::Ip;
+        Key {
+            global,
+            // A catcher's own limit is never shared with other catchers.
+            catcher: Some(catcher.path.clone()).filter(|_| !global || !per_ip),
+            ip: ip.filter(|_| per_ip).map(network),
+        }
+    }
+}
+
+// The address itself for IPv4, but the /64 network for IPv6, since a single
+// client usually has all of one.
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::from(u64::MAX));
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+// `Duration::from_secs_f64`, but saturating instead of panicking.
+fn secs(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Its key in `Buckets.used`.
+    used: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst());
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+pub struct Limiter {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<Key, Bucket>,
+    // The buckets in the order they were last used in.
+    used: BTreeMap<u64, Key>,
+    uses: u64,
+}
+
+impl Buckets {
+    // The bucket for `key`, marked as just used.
+    fn get(&mut self, key: &Key, limit: &RateLimit, now: Instant) -> &mut Bucket {
+        self.uses += 1;
+        let uses = self.uses;
+        if let Some(bucket) = self.map.get_mut(key) {
+            self.used.remove(&bucket.used);
+            self.used.insert(uses, key.clone());
+            bucket.used = uses;
+            return self.map.get_mut(key).unwrap();
+        }
+        if self.map.len() >= MAX_BUCKETS {
+            if let Some((_, oldest)) = self.used.pop_first() {
+                self.map.remove(&oldest);
+            }
+        }
+        self.used.insert(uses, key.clone());
+        self.map.entry(key.clone()).or_insert(Bucket {
+            tokens: limit.burst(),
+            updated: now,
+            used: uses,
+        })
+    }
+}
+
+impl Limiter {
+    // Takes a request's worth from every bucket a request to `catcher` from
+    // `ip` falls in, if they all have enough left. Otherwise, returns how long
+    // until they will.
+    pub fn check(
+        &self,
+        config: &Config,
+        catcher: &Catcher,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Duration> {
+        let limits: Vec<_> = config
+            .rate_limit
+            .iter()
+            .map(|limit| (Key::new(true, limit, catcher, ip), limit))
+            .chain(
+                catcher
+                    .rate_limit
+                    .iter()
+                    .map(|limit| (Key::new(false, limit, catcher, ip), limit)),
+            )
+            .collect();
+        if limits.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut wait = Duration::ZERO;
+        for (key, limit) in &limits {
+            let bucket = buckets.get(key, limit, now);
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(secs((1.0 - bucket.tokens) / limit.rate));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (key, limit) in &limits {
+            buckets.get(key, limit, now).tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+// Reloading the configuration while running, on SIGHUP or when it changes.
+
+use crate::{
+    config::{Config, Format, INCLUDE_DIR},
+    logging, tls, State,
+};
+
+use anyhow::Context;
+use log::{error, info};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+// How long to wait for more changes before reloading, since saving a file
+// usually shows up as several.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+fn reload(state: &State) {
+    match state.config.reload() {
+        Ok(config) => {
+            logging::configure(&config.log);
+            info!(
+                "Reloaded configuration with {} catcher(s)",
+                config.catchers.len()
+            );
+        }
+        Err(e) => error!("Keeping the old configuration: {:#}", e),
+    }
+}
+
+// Also reloads the TLS certificate on SIGHUP, if there is one.
+pub fn spawn(
+    state: &'static State,
+    watch: bool,
+    certs: Option<Arc<tls::Certs>>,
+) -> Result<(), anyhow::Error> {
+    let mut hangups = signal(SignalKind::hangup()).context("Could not listen for SIGHUP")?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Got SIGHUP, reloading configuration");
+            reload(state);
+            if let Some(certs) = &certs {
+                certs.reload();
+            }
+        }
+    });
+
+    if watch {
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                match event {
+                    // Reading the configuration shows up as an access, so those
+                    // have to be left out.
+                    Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                        let relevant = event
+                            .paths
+                            .iter()
+                            .any(|path| Format::from_extension(path).is_some() || path.is_dir());
+                        if relevant {
+                            let _ = sender.send(());
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Could not watch configuration: {}", e),
+                }
+            })
+            .context("Could not watch configuration")?;
+        let mut watched = watch_dirs(&mut watcher, &state.config.get(), BTreeSet::new());
+        tokio::spawn(async move {
+            while changes.recv().await.is_some() {
+                tokio::time::sleep(SETTLE_TIME).await;
+                while changes.try_recv().is_ok() {}
+                info!("Configuration changed, reloading");
+                reload(state);
+                watched = watch_dirs(&mut watcher, &state.config.get(), watched);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Watches the directories the files of `config` are in, as well as the
+// directories they include, instead of `watched`. Directories are watched
+// rather than files since editors often replace files instead of changing
+// them.
+fn watch_dirs(
+    watcher: &mut RecommendedWatcher,
+    config: &Config,
+    watched: BTreeSet<PathBuf>,
+) -> BTreeSet<PathBuf> {
+    let dir_of = |file: &PathBuf| match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mut dirs: BTreeSet<_> = config.files.iter().map(dir_of).collect();
+    if let Some(cfg_dir) = config.files.first().map(dir_of) {
+        dirs.insert(cfg_dir.join(INCLUDE_DIR));
+        for include in &config.include {
+            dirs.insert(cfg_dir.join(include));
+        }
+    }
+    dirs.retain(|dir| dir.is_dir());
+
+    for dir in watched.difference(&dirs) {
+        let _ = watcher.unwatch(dir);
+    }
+    for dir in dirs.difference(&watched) {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            error!("Could not watch {}: {}", dir.display(), e);
+        }
+    }
+    dirs
+}
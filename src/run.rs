@@ -0,0 +1,205 @@
+// Running the `.run` command of a catcher.
+
+use crate::{
+    config::Catcher,
+    metrics,
+    payload::{self, Payload},
+    systemd,
+};
+
+use anyhow::Context;
+use std::{
+    collections::BTreeSet,
+    io,
+    ops::{Deref, DerefMut},
+    process::{ExitStatus, Output, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    process::Command,
+    sync::watch,
+    time::{sleep, Instant},
+};
+
+// The environment variable telling `.run` which attempt this is (starting at 1).
+pub const ATTEMPT_VAR: &str = "WEBHOOKEE_ATTEMPT";
+
+// The `.run` commands that haven't finished yet, so that shutting down can
+// wait for them.
+#[derive(Default)]
+pub struct Children {
+    // Each one leads its own process group, with the same ID.
+    pids: Mutex<BTreeSet<u32>>,
+    stopping: watch::Sender<bool>,
+}
+
+impl Children {
+    pub fn count(&self) -> usize {
+        self.pids.lock().unwrap().len()
+    }
+
+    // Marks webhookee as shutting down, after which commands that fail are
+    // expected to have been terminated.
+    pub fn stop(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    pub fn stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    // Returns once `stop` has been called.
+    pub async fn stopped(&self) {
+        let _ = self
+            .stopping
+            .subscribe()
+            .wait_for(|&stopping| stopping)
+            .await;
+    }
+
+    // Waits for all of them to finish, for at most `timeout`. Returns whether
+    // they did.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.count() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    // Sends `signal` to all of them, along with anything they started.
+    pub fn kill(&self, signal: libc::c_int) {
+        for &pid in self.pids.lock().unwrap().iter() {
+            // SAFETY: just a system call. Processes are forgotten as soon as
+            // they have been waited for, which only leaves a tiny window in
+            // which the process group could have been reused.
+            unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+        }
+    }
+}
+
+// A running `.run` command, which counts towards `Children` until it has been
+// waited for.
+pub struct Child {
+    child: tokio::process::Child,
+    // The path of the catcher, for metrics.
+    catcher: String,
+    started: Instant,
+    tracked: Tracked,
+}
+
+struct Tracked {
+    pid: Option<u32>,
+    children: Arc<Children>,
+}
+
+impl Tracked {
+    // Forgets the process, once it has been waited for and its ID can be
+    // reused.
+    fn untrack(&mut self) {
+        if let Some(pid) = self.pid.take() {
+            self.children.pids.lock().unwrap().remove(&pid);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.untrack();
+    }
+}
+
+impl Deref for Child {
+    type Target = tokio::process::Child;
+
+    fn deref(&self) -> &Self::Target {
+        &self.child
+    }
+}
+
+impl DerefMut for Child {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.child
+    }
+}
+
+impl Child {
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait().await;
+        self.tracked.untrack();
+        let status = status?;
+        metrics::run(&self.catcher, status, self.started.elapsed());
+        Ok(status)
+    }
+
+    pub async fn wait_with_output(self) -> io::Result<Output> {
+        let Child {
+            child,
+            catcher,
+            started,
+            mut tracked,
+        } = self;
+        let output = child.wait_with_output().await;
+        tracked.untrack();
+        let output = output?;
+        metrics::run(&catcher, output.status, started.elapsed());
+        Ok(output)
+    }
+}
+
+// Runs `cmd` with `/bin/sh -c`.
+pub fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(cmd);
+    for var in systemd::LISTEN_VARS {
+        command.env_remove(var);
+    }
+    command
+}
+
+// Starts the `.run` command of `catcher` and passes it the request payload.
+// Its standard output is left piped for the caller.
+pub async fn spawn(
+    children: &Arc<Children>,
+    catcher: &Catcher,
+    req_payload: &Payload,
+    attempt: u32,
+    stderr: Stdio,
+) -> Result<Child, anyhow::Error> {
+    let started = Instant::now();
+    let mut run = shell(&catcher.run)
+        .env(ATTEMPT_VAR, attempt.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(stderr)
+        // Otherwise a Ctrl-C in the terminal would interrupt it right away,
+        // instead of letting it finish while shutting down.
+        .process_group(0)
+        .spawn()
+        .context("Could not execute .run command")?;
+    let pid = run.id();
+    if let Some(pid) = pid {
+        children.pids.lock().unwrap().insert(pid);
+    }
+    let tracked = Tracked {
+        pid,
+        children: children.clone(),
+    };
+    let stdin = run
+        .stdin
+        .take()
+        .context("Could not get stdin handle of run")?;
+    payload::write_to(stdin, req_payload)
+        .await
+        .context("Could not pass request payload to run command")?;
+    Ok(Child {
+        child: run,
+        catcher: catcher.path.clone(),
+        started,
+        tracked,
+    })
+}
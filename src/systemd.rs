@@ -0,0 +1,122 @@
+// Running as a systemd service: socket activation (`LISTEN_FDS`) and
+// readiness notifications (`NOTIFY_SOCKET`).
+
+use crate::listen::{Incoming, Listener};
+
+use anyhow::Context;
+use hyper::server::conn::AddrIncoming;
+use log::{error, info};
+use std::{
+    env, mem,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            io::{FromRawFd, RawFd},
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
+
+// The first file descriptor systemd passes.
+const LISTEN_FDS_START: RawFd = 3;
+
+// What systemd tells us about the sockets it passes, which commands webhookee
+// runs shouldn't think are for them.
+pub const LISTEN_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+// Takes the sockets systemd passed, if any. They are named
+// `systemd:<FileDescriptorName>`.
+pub fn listeners() -> Result<Vec<Listener>, anyhow::Error> {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = env::var("LISTEN_FDS").ok().filter(|_| for_us);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let count: RawFd = match count {
+        Some(count) => count.parse().context("Invalid LISTEN_FDS")?,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut names = names.split(':');
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = format!("systemd:{}", names.next().unwrap_or("unknown"));
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        // SAFETY: systemd hands these file descriptors over to us, and
+        // nothing else uses them.
+        let incoming = unsafe { take_socket(fd) }
+            .with_context(|| format!("Could not listen on {} (file descriptor {})", name, fd))?;
+        info!("Listening on {}", name);
+        listeners.push(Listener {
+            name,
+            incoming,
+            socket_file: None,
+        });
+    }
+    Ok(listeners)
+}
+
+unsafe fn take_socket(fd: RawFd) -> Result<Incoming, anyhow::Error> {
+    if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut addr: libc::sockaddr_storage = mem::zeroed();
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    match addr.ss_family as libc::c_int {
+        libc::AF_UNIX => {
+            let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Incoming::Unix(tokio::net::UnixListener::from_std(
+                listener,
+            )?))
+        }
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = std::net::TcpListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Incoming::Tcp(AddrIncoming::from_listener(
+                tokio::net::TcpListener::from_std(listener)?,
+            )?))
+        }
+        family => anyhow::bail!("Unsupported socket family {}", family),
+    }
+}
+
+// Tells systemd about the state of the service, if it is listening.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let send = || -> std::io::Result<()> {
+        let addr = match path.to_str().and_then(|path| path.strip_prefix('@')) {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    };
+    if let Err(e) = send() {
+        error!("Could not notify systemd: {}", e);
+    }
+}
+
+// Pings the systemd watchdog for as long as webhookee runs, if it is enabled.
+pub fn spawn_watchdog() {
+    let for_us = env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+    let usec = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        // Like `sd_watchdog_enabled`, zero means there is no watchdog.
+        .filter(|&usec: &u64| usec > 0);
+    if let (true, Some(usec)) = (for_us, usec) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+            loop {
+                interval.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
@@ -0,0 +1,295 @@
+// Serving HTTPS.
+
+use crate::{
+    config,
+    listen::{self, Peer},
+    payload::ClientCert,
+};
+
+use anyhow::Context;
+use hyper::server::accept::{self, Accept};
+use log::{error, info};
+use std::{
+    convert::TryFrom,
+    fmt, fs,
+    io::{self, BufReader},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::{default_provider, sign::any_supported_type},
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor, TlsConnector,
+};
+
+// How often to check whether the certificate has changed, if `.tls.reload`
+// is set.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The certificate and key, which can be swapped out while running.
+pub struct Certs {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // When the files were last changed, as of the last (re)load.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl fmt::Debug for Certs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Certs")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl Certs {
+    fn open(tls: &config::Tls) -> Result<Certs, anyhow::Error> {
+        let certs = Certs {
+            cert_path: tls.cert.clone(),
+            key_path: tls.key.clone(),
+            current: RwLock::new(Arc::new(load(&tls.cert, &tls.key)?)),
+            modified: Mutex::new((None, None)),
+        };
+        *certs.modified.lock().unwrap() = certs.modified();
+        Ok(certs)
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+
+    // Reads the certificate and key again, keeping the old ones if they
+    // can't be loaded.
+    pub fn reload(&self) {
+        *self.modified.lock().unwrap() = self.modified();
+        match load(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                info!("Reloaded TLS certificate");
+            }
+            Err(e) => error!("Keeping the old TLS certificate: {:#}", e),
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        if *self.modified.lock().unwrap() != self.modified() {
+            self.reload();
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<fs::File>, anyhow::Error> {
+    fs::File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Could not open {}", path.display()))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Could not read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, anyhow::Error> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .with_context(|| format!("Could not read private key from {}", path.display()))?
+        .ok_or_else(|| anyhow::Error::msg(format!("No private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, anyhow::Error> {
+    let certs = load_certs(cert_path)?;
+    let key = any_supported_type(&load_key(key_path)?).context("Unsupported private key")?;
+    // Catches a renewal that has only been half written yet.
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().with_context(|| {
+        format!(
+            "The private key in {} does not match the certificate in {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })?;
+    Ok(certified)
+}
+
+// Sets up TLS as configured, checking for a new certificate in the background
+// if `.tls.reload` is set.
+pub fn acceptor(tls: &config::Tls) -> Result<(TlsAcceptor, Arc<Certs>), anyhow::Error> {
+    let certs = Arc::new(Certs::open(tls)?);
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Could not set up TLS")?;
+    let builder = match &tls.client_ca {
+        // Clients without a certificate are let through here, it's up to the
+        // catcher whether it wants one.
+        Some(client_ca) => {
+            let roots = load_roots(client_ca)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .context("Could not set up client certificate validation")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(certs.clone());
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if tls.reload {
+        let certs = certs.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                certs.reload_if_changed();
+            }
+        });
+    }
+    Ok((TlsAcceptor::from(Arc::new(server_config)), certs))
+}
+
+// Sets up TLS for connecting to a webhookee instance, trusting the CA
+// certificates in `roots`, and presenting `client_cert` (a certificate and
+// its key) if given.
+pub fn connector(
+    roots: &Path,
+    client_cert: Option<(&Path, &Path)>,
+) -> Result<TlsConnector, anyhow::Error> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Could not set up TLS")?
+        .with_root_certificates(load_roots(roots)?);
+    let client_config = match client_cert {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .context("Could not use client certificate")?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+// Does the TLS handshake for each connection from `incoming`, in the
+// background so that slow clients don't hold up the others.
+pub fn accept<I>(
+    incoming: I,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = TlsStream<I::Conn>, Error = io::Error>
+where
+    I: Accept + Unpin + Send + 'static,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: fmt::Display + Send,
+{
+    let (sender, mut receiver) = mpsc::channel(16);
+    let mut incoming = listen::retry_errors(incoming);
+    tokio::spawn(async move {
+        // Errors have already been logged and retried.
+        while let Some(Ok(conn)) =
+            std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await
+        {
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(stream)) => {
+                        // The server has stopped if this fails.
+                        let _ = sender.send(stream).await;
+                    }
+                    Ok(Err(e)) => info!("TLS handshake failed: {}", e),
+                    Err(_) => info!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    accept::poll_fn(move |cx| receiver.poll_recv(cx).map(|stream| stream.map(Ok)))
+}
+
+impl<IO: Peer> Peer for TlsStream<IO> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        let der = self.get_ref().1.peer_certificates()?.first()?;
+        match parse_client_cert(der) {
+            Ok(cert) => Some(cert),
+            Err(e) => {
+                error!("Could not parse client certificate: {}", e);
+                None
+            }
+        }
+    }
+}
+
+fn parse_client_cert(der: &[u8]) -> Result<ClientCert, anyhow::Error> {
+    use x509_parser::extensions::GeneralName;
+
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_owned);
+    let mut san = Vec::new();
+    if let Some(ext) = cert.subject_alternative_name()? {
+        for name in &ext.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => san.push((*name).to_owned()),
+                GeneralName::IPAddress(ip) => {
+                    if let Ok(ip) = <[u8; 4]>::try_from(*ip) {
+                        san.push(IpAddr::from(ip).to_string());
+                    } else if let Ok(ip) = <[u8; 16]>::try_from(*ip) {
+                        san.push(IpAddr::from(ip).to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(ClientCert {
+        subject: cert.subject().to_string(),
+        common_name,
+        san,
+        fingerprint: hex::encode(ring::digest::digest(&ring::digest::SHA256, der)),
+    })
+}
@@ -1,13 +1,13 @@
 use crate::{
-    config::{self, Catcher},
-    payload::Payload,
+    config::{self, Catcher, Secret},
+    payload::{self, Payload},
+    run,
 };
 
 use anyhow::Context;
-use hyper::HeaderMap;
+use hyper::{header::HeaderValue, HeaderMap};
 use ring::hmac;
-use std::{env, process::Stdio};
-use tokio::{io::AsyncWriteExt, process::Command};
+use std::{borrow::Cow, env, process::Stdio};
 
 fn validate_github(headers: &HeaderMap, body: &[u8], secret: &[u8]) -> bool {
     let sent_hash_hex = match headers.get("x-hub-signature-256") {
@@ -29,60 +29,78 @@ pub async fn validate(catcher: &Catcher, req_payload: &Payload) -> Result<bool,
         config::Validate::Dont => Ok(true),
         config::Validate::Command(cmd) => {
             // XXX maybe make crossplatform later on?
-            let req_payload_str = serde_json::to_string(req_payload)
-                .context("Could not serialize request payload")?;
-            let mut validator = Command::new("/bin/sh")
-                .arg("-c")
-                .arg(cmd)
+            let mut validator = run::shell(cmd)
                 .stdin(Stdio::piped())
                 .spawn()
                 .context("Could not execute validation process")?;
-            {
-                let mut stdin = validator
-                    .stdin
-                    .take()
-                    .expect("Could not take standard input of validation process");
-                stdin
-                    .write_all(req_payload_str.as_bytes())
-                    .await
-                    .context("Could not write request payload")?;
-                // Close standard input
-                drop(stdin);
-            }
+            let stdin = validator
+                .stdin
+                .take()
+                .expect("Could not take standard input of validation process");
+            payload::write_to(stdin, req_payload).await?;
             let status = validator
                 .wait()
                 .await
                 .context("Could not wait for validator to exit")?;
             Ok(status.code() == Some(0))
         }
-        config::Validate::GitHub(keyspec) => {
+        config::Validate::GitHub(secret) => {
             if let Some(body) = &req_payload.body {
-                let mut keyspec_chars = keyspec.chars();
-                if keyspec_chars.next() == Some('$') {
-                    // It's an environment variable.
-                    let key_var = keyspec_chars.as_str();
-                    let key = env::var(key_var).map_err(|_| {
-                        anyhow::Error::msg(format!(
-                            "Could not resolve environment variable {} or was not valid UTF-8",
-                            key_var
-                        ))
-                    })?;
-                    Ok(validate_github(
-                        &req_payload.headers.0,
-                        body.as_bytes(),
-                        key.as_bytes(),
-                    ))
-                } else {
-                    Ok(validate_github(
-                        &req_payload.headers.0,
-                        body.as_bytes(),
-                        keyspec.as_bytes(),
-                    ))
-                }
+                let key = resolve_key(secret)?;
+                Ok(validate_github(
+                    &req_payload.headers.0,
+                    body.as_bytes(),
+                    key.as_bytes(),
+                ))
             } else {
                 // GitHub webhooks are always POST requests.
                 Ok(false)
             }
         }
+        // The certificate has already been checked against `.tls.client_ca`
+        // during the TLS handshake.
+        config::Validate::ClientCert(names) => Ok(match &req_payload.client_cert {
+            Some(cert) => names.is_empty() || names.iter().any(|name| cert.has_name(name)),
+            None => false,
+        }),
+    }
+}
+
+// Resolves a secret key, reading it from its environment variable if it
+// comes from one.
+fn resolve_key(secret: &Secret) -> Result<Cow<'_, str>, anyhow::Error> {
+    match secret {
+        Secret::Var(key_var) => env::var(key_var).map(Cow::Owned).map_err(|_| {
+            anyhow::Error::msg(format!(
+                "Could not resolve environment variable {} or was not valid UTF-8",
+                key_var
+            ))
+        }),
+        Secret::Key(key) => Ok(Cow::Borrowed(key)),
+    }
+}
+
+// Adds whatever headers a request with this body needs to pass the catcher's
+// validation. Returns false if the validation method can't be satisfied this
+// way (i.e. it is a command or a client certificate).
+pub fn sign(
+    catcher: &Catcher,
+    headers: &mut HeaderMap,
+    body: &[u8],
+) -> Result<bool, anyhow::Error> {
+    match &catcher.validate {
+        config::Validate::Dont => Ok(true),
+        config::Validate::Command(_) | config::Validate::ClientCert(_) => Ok(false),
+        config::Validate::GitHub(secret) => {
+            let key = resolve_key(secret)?;
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+            let signature = hex::encode(hmac::sign(&key, body));
+            headers.insert(
+                "x-hub-signature-256",
+                HeaderValue::from_str(&format!("sha256={}", signature))
+                    .expect("Hex is always a valid header value"),
+            );
+            Ok(true)
+        }
     }
 }
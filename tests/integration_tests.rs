@@ -1,14 +1,17 @@
+use hyper::body::HttpBody;
 use hyper::http::Uri;
 use hyper::{client::HttpConnector, Client};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use process_control::ChildExt;
 use std::ffi::OsStr;
 use std::future::Future;
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, Output};
 use std::time::Duration;
 use tempfile::TempDir;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
+use tokio::net::UnixDatagram;
 use tokio::test;
 
 fn local_uri(port: u16, path: &str) -> Uri {
@@ -18,7 +21,7 @@ fn local_uri(port: u16, path: &str) -> Uri {
 }
 
 async fn full_body(body: Body) -> String {
-    String::from_utf8((&hyper::body::to_bytes(body).await.unwrap()).to_vec()).unwrap()
+    String::from_utf8(hyper::body::to_bytes(body).await.unwrap().to_vec()).unwrap()
 }
 
 const TIMEOUT_SECS: u64 = 2;
@@ -37,6 +40,23 @@ async fn get(client: &Client<HttpConnector>, uri: Uri) -> Result<Response<Body>,
         .unwrap()
 }
 
+// Waits for webhookee to tell systemd (that is, us) that it is ready.
+async fn wait_until_ready(notify_socket: &UnixDatagram) {
+    let mut buf = [0; 256];
+    loop {
+        let len = tokio::time::timeout(Duration::from_secs(10), notify_socket.recv(&mut buf))
+            .await
+            .expect("webhookee did not become ready")
+            .unwrap();
+        if buf[..len]
+            .split(|&b| b == b'\n')
+            .any(|line| line == b"READY=1")
+        {
+            return;
+        }
+    }
+}
+
 async fn invoke<F, Fut>(config: &str, f: F)
 where
     F: FnOnce(Client<HttpConnector>) -> Fut,
@@ -50,10 +70,33 @@ where
     F: FnOnce(Client<HttpConnector>) -> Fut,
     Fut: Future<Output = ()>,
 {
-    let path_to_webhookee = assert_cmd::cargo::cargo_bin("webhookee");
     let tmp_dir = TempDir::new().expect("Could not create temporary dir");
-    let cfg_file_path = tmp_dir.path().join("config.json");
-    let log_file_path = tmp_dir.path().join("log");
+    invoke_in(tmp_dir.path(), env, config, f).await
+}
+
+// Runs webhookee with its configuration, logs and data directory (`data`) in `dir`.
+async fn invoke_in<F, Fut>(dir: &Path, env: &[(&str, &str)], config: &str, f: F)
+where
+    F: FnOnce(Client<HttpConnector>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    invoke_with_config_file(dir, env, &[], "config.json", config, f).await
+}
+
+async fn invoke_with_config_file<F, Fut>(
+    dir: &Path,
+    env: &[(&str, &str)],
+    args: &[&str],
+    cfg_file_name: &str,
+    config: &str,
+    f: F,
+) where
+    F: FnOnce(Client<HttpConnector>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let path_to_webhookee = assert_cmd::cargo::cargo_bin("webhookee");
+    let cfg_file_path = dir.join(cfg_file_name);
+    let log_file_path = dir.join("log");
     let mut cfg_file: File = OpenOptions::new()
         .read(true)
         .write(true)
@@ -62,17 +105,22 @@ where
         .await
         .unwrap();
     cfg_file.write_all(config.as_bytes()).await.unwrap();
+    let notify_socket_path = dir.join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_socket_path).unwrap();
     let mut child = Command::new(path_to_webhookee)
         .arg("--config")
         .arg(&cfg_file_path)
         .arg("--log-file")
         .arg(&log_file_path)
+        .arg("--data-dir")
+        .arg(dir.join("data"))
+        .args(args)
+        .env("NOTIFY_SOCKET", &notify_socket_path)
         .envs(env.iter().map(|(k, v)| (OsStr::new(k), OsStr::new(v))))
         .spawn()
         .expect("Failed to execute webhookee");
 
-    // Wait for the server to come up.
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    wait_until_ready(&notify_socket).await;
 
     // We want to make *sure* the child dies.
     let hook = std::panic::take_hook();
@@ -90,6 +138,25 @@ where
     let _ = child.kill();
 }
 
+// Runs a webhookee subcommand against the state left by `invoke_in(dir, ...)`.
+fn cli(dir: &Path, args: &[&str]) -> Output {
+    Command::new(assert_cmd::cargo::cargo_bin("webhookee"))
+        .arg("--config")
+        .arg(dir.join("config.json"))
+        .arg("--data-dir")
+        .arg(dir.join("data"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Like `cli`, but also checks that it succeeded and returns its output.
+fn run_cli(dir: &Path, args: &[&str]) -> String {
+    let output = cli(dir, args);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
 #[test]
 async fn echo() {
     // The webhook will echo any GET or POST request.
@@ -185,7 +252,10 @@ async fn empty_body_on_get() {
 async fn github_validation() {
     // The example key used here is completely random.
     invoke_with_env(
-        &[("KEY", "7211fd007695d5110dc4e0a5334730f90118ac26")],
+        &[
+            ("KEY", "7211fd007695d5110dc4e0a5334730f90118ac26"),
+            ("DOLLAR_KEY", "$KEY"),
+        ],
         r#"{
     "port": 3021,
     "catchers": [{
@@ -198,23 +268,35 @@ async fn github_validation() {
         "path": "/test/github_validation_env",
         "run": "printf env",
         "validate": ["github", "$KEY"]
+    }, {
+        "methods": ["POST"],
+        "path": "/test/github_validation_interpolated",
+        "run": "printf interpolated",
+        "validate": ["github", "${DOLLAR_KEY}"]
     }]
 }"#,
         |client| async move {
-            for suffix in ["raw", "env"] {
+            // Generated via
+            // ```py
+            // hmac.new(bytes('7211fd007695d5110dc4e0a5334730f90118ac26', 'utf-8'),
+            // msg=bytes('{}', 'utf-8'), digestmod='sha256').hexdigest()
+            // ```
+            let signature =
+                "sha256=814c5791a2a1a868bdb1d14c0072ad6955883df5f0aa4f86918ae82d88f059c2";
+            // The same with the key `$KEY`, which is what `${DOLLAR_KEY}`
+            // expands to.
+            let dollar_signature =
+                "sha256=a628dcb835771eb54fec6fa408b830fd0dcd78d28ebab5986cae8400eb7982b7";
+            for (suffix, signature) in [
+                ("raw", signature),
+                ("env", signature),
+                ("interpolated", dollar_signature),
+            ] {
                 let uri = local_uri(3021, &("/test/github_validation_".to_owned() + suffix));
                 let req = Request::builder()
                     .method("POST")
                     .uri(uri)
-                    // Generated via
-                    // ```py
-                    // hmac.new(bytes('7211fd007695d5110dc4e0a5334730f90118ac26', 'utf-8'),
-                    // msg=bytes('{}', 'utf-8'), digestmod='sha256').hexdigest()
-                    // ```
-                    .header(
-                        "X-Hub-Signature-256",
-                        "sha256=814c5791a2a1a868bdb1d14c0072ad6955883df5f0aa4f86918ae82d88f059c2",
-                    )
+                    .header("X-Hub-Signature-256", signature)
                     .body(Body::from("{}"))
                     .unwrap();
                 let res = send_req(&client, req).await.unwrap();
@@ -225,3 +307,1495 @@ async fn github_validation() {
     )
     .await;
 }
+
+#[test]
+async fn streamed_response() {
+    invoke(
+        r#"{
+    "port": 3022,
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/stream",
+        "run": "printf 'one\\n'; sleep 1; printf 'two\\n'",
+        "validate": false,
+        "response": "stream"
+    }, {
+        "methods": ["GET"],
+        "path": "/test/sse",
+        "run": "printf 'one\\ntwo\\n'",
+        "validate": false,
+        "response": "sse"
+    }]
+}"#,
+        |client| async move {
+            let res = get(&client, local_uri(3022, "/test/stream")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            // The first chunk should arrive before the command has finished.
+            let mut body = res.into_body();
+            let first = tokio::time::timeout(Duration::from_millis(500), body.data())
+                .await
+                .expect("first chunk was not streamed")
+                .unwrap()
+                .unwrap();
+            assert_eq!(&first[..], b"one\n");
+            assert_eq!(full_body(body).await, "two\n");
+
+            let res = get(&client, local_uri(3022, "/test/sse")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["content-type"], "text/event-stream");
+            assert_eq!(
+                full_body(res.into_body()).await,
+                "data: one\n\ndata: two\n\n"
+            );
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn async_retry() {
+    let out_dir = TempDir::new().unwrap();
+    let attempts_path = out_dir.path().join("attempts");
+    invoke(
+        &format!(
+            r#"{{
+    "port": 3023,
+    "catchers": [{{
+        "methods": ["POST"],
+        "path": "/test/async_retry",
+        "run": "echo $WEBHOOKEE_ATTEMPT >> {}; [ $WEBHOOKEE_ATTEMPT -ge 3 ]",
+        "validate": false,
+        "async": true,
+        "retry": {{ "max_attempts": 5, "backoff": 0.1 }}
+    }}]
+}}"#,
+            attempts_path.display()
+        ),
+        |client| async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri(local_uri(3023, "/test/async_retry"))
+                .body(Body::empty())
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            // It should stop retrying once the third attempt succeeds.
+            assert_eq!(
+                tokio::fs::read_to_string(&attempts_path).await.unwrap(),
+                "1\n2\n3\n"
+            );
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn resume_queued_job() {
+    let tmp_dir = TempDir::new().unwrap();
+    let out_path = tmp_dir.path().join("out");
+    let payload_path = tmp_dir.path().join("payload");
+    // A job left behind by a previous instance, after one failed attempt.
+    let queue_dir = tmp_dir.path().join("data").join("queue");
+    std::fs::create_dir_all(&queue_dir).unwrap();
+    std::fs::write(
+        queue_dir.join("0123456789abcdef.json"),
+        r#"{
+    "id": "0123456789abcdef",
+    "queued_at": 0,
+    "attempts": 1,
+    "payload": {
+        "method": "POST",
+        "path": "/test/resume",
+        "headers": {"x-tag": "a", "x-tag": "b"},
+        "body": "left over"
+    }
+}"#,
+    )
+    .unwrap();
+    // And one that had already run out of attempts.
+    std::fs::write(
+        queue_dir.join("fedcba9876543210.json"),
+        r#"{
+    "id": "fedcba9876543210",
+    "queued_at": 1,
+    "attempts": 3,
+    "payload": {
+        "method": "POST",
+        "path": "/test/resume",
+        "headers": [["x-tag", "c"]],
+        "body": "out of attempts"
+    }
+}"#,
+    )
+    .unwrap();
+    invoke_in(
+        tmp_dir.path(),
+        &[],
+        &format!(
+            r#"{{
+    "port": 3024,
+    "catchers": [{{
+        "methods": ["POST"],
+        "path": "/test/resume",
+        "run": "tee {} | jq -j .body > {}; echo $WEBHOOKEE_ATTEMPT >> {1}",
+        "validate": false,
+        "async": true,
+        "retry": {{ "max_attempts": 3 }}
+    }}]
+}}"#,
+            payload_path.display(),
+            out_path.display()
+        ),
+        |_client| async move {
+            // The job is picked up as soon as webhookee starts.
+            for _ in 0..20 {
+                if std::fs::read_dir(&queue_dir).unwrap().next().is_none() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            assert_eq!(
+                tokio::fs::read_to_string(&out_path).await.unwrap(),
+                "left over2\n"
+            );
+            // Repeated headers are all still there.
+            let payload = std::fs::read_to_string(&payload_path).unwrap();
+            assert!(
+                payload.contains(r#""x-tag":"a","x-tag":"b""#),
+                "{}",
+                payload
+            );
+            assert!(std::fs::read_dir(&queue_dir).unwrap().next().is_none());
+        },
+    )
+    .await;
+    let dead_letters = run_cli(tmp_dir.path(), &["dead-letter", "list"]);
+    assert!(dead_letters.starts_with("fedcba9876543210  "));
+    assert!(dead_letters.contains("POST /test/resume  3 attempt(s), Out of attempts"));
+}
+
+#[test]
+async fn dead_letter() {
+    let tmp_dir = TempDir::new().unwrap();
+    let marker_path = tmp_dir.path().join("marker");
+    invoke_in(
+        tmp_dir.path(),
+        &[],
+        &format!(
+            r#"{{
+    "port": 3025,
+    "catchers": [{{
+        "methods": ["POST"],
+        "path": "/test/dead_letter",
+        "run": "echo nope >&2; [ -e {} ]",
+        "validate": false,
+        "async": true,
+        "retry": {{ "max_attempts": 2, "backoff": 0.1 }}
+    }}]
+}}"#,
+            marker_path.display()
+        ),
+        |client| async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri(local_uri(3025, "/test/dead_letter"))
+                .body(Body::empty())
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        },
+    )
+    .await;
+
+    let dead_letter = |args: &[&str]| {
+        let args: Vec<_> = std::iter::once("dead-letter")
+            .chain(args.iter().copied())
+            .collect();
+        run_cli(tmp_dir.path(), &args)
+    };
+    let list = dead_letter(&["list"]);
+    assert!(list.contains("POST /test/dead_letter  2 attempt(s), exit status: 1"));
+    let id = list.split_whitespace().next().unwrap().to_owned();
+    let dead_job: serde_json::Value = serde_json::from_str(&dead_letter(&["show", &id])).unwrap();
+    assert_eq!(dead_job["last_failure"]["stderr"], "nope\n");
+
+    // Once whatever was wrong is fixed, it can be run again.
+    std::fs::write(&marker_path, "").unwrap();
+    dead_letter(&["retry", &id]);
+    assert_eq!(dead_letter(&["list"]), "");
+}
+
+#[test]
+async fn history() {
+    let tmp_dir = TempDir::new().unwrap();
+    invoke_in(
+        tmp_dir.path(),
+        &[],
+        r#"{
+    "port": 3026,
+    "history": {"max_body_size": 5},
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/history",
+        "run": "jq -j .body",
+        "validate": "[ \"$(jq -r .body)\" != denied ]"
+    }]
+}"#,
+        |client| async move {
+            for body in ["allowed", "denied"] {
+                let req = Request::builder()
+                    .method("POST")
+                    .uri(local_uri(3026, "/test/history"))
+                    .header("authorization", "Bearer secret")
+                    .body(Body::from(body))
+                    .unwrap();
+                send_req(&client, req).await.unwrap();
+            }
+        },
+    )
+    .await;
+
+    let history = run_cli(tmp_dir.path(), &["history"]);
+    let lines: Vec<_> = history.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("POST /test/history  validation failed, not run"));
+    assert!(lines[1].ends_with("POST /test/history  validation passed, exit status: 0"));
+    let id = lines[1].split_whitespace().next().unwrap();
+    let entry: serde_json::Value =
+        serde_json::from_str(&run_cli(tmp_dir.path(), &["show", id])).unwrap();
+    assert_eq!(entry["output"], "allowed");
+    assert_eq!(entry["payload"]["body"], "allow");
+    assert_eq!(entry["body_truncated"], true);
+    assert_eq!(entry["payload"]["headers"]["authorization"], "[redacted]");
+    assert!(!cli(tmp_dir.path(), &["replay", id]).status.success());
+}
+
+#[test]
+async fn replay() {
+    let tmp_dir = TempDir::new().unwrap();
+    invoke_in(
+        tmp_dir.path(),
+        &[],
+        r#"{
+    "port": 3027,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/replay",
+        "run": "jq -j .body | tr a-z A-Z",
+        "validate": "[ \"$(jq -r .body)\" = ok ]"
+    }]
+}"#,
+        |client| async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri(local_uri(3027, "/test/replay"))
+                .body(Body::from("ok"))
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(full_body(res.into_body()).await, "OK");
+        },
+    )
+    .await;
+
+    // From the history...
+    let history = run_cli(tmp_dir.path(), &["history"]);
+    let id = history.split_whitespace().next().unwrap();
+    assert_eq!(run_cli(tmp_dir.path(), &["replay", id]), "OK");
+
+    // ...or from a file.
+    let payload_path = tmp_dir.path().join("payload.json");
+    std::fs::write(
+        &payload_path,
+        r#"{ "method": "POST", "path": "/test/replay", "headers": {}, "body": "not ok" }"#,
+    )
+    .unwrap();
+    let payload_path = payload_path.to_str().unwrap();
+    assert!(!cli(tmp_dir.path(), &["replay", payload_path])
+        .status
+        .success());
+    assert_eq!(
+        run_cli(
+            tmp_dir.path(),
+            &["replay", "--skip-validation", payload_path]
+        ),
+        "NOT OK"
+    );
+}
+
+#[test]
+async fn send() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path().to_owned();
+    invoke_in(
+        tmp_dir.path(),
+        &[],
+        r#"{
+    "port": 3028,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/send",
+        "run": "jq -j .body",
+        "validate": ["github", "7211fd007695d5110dc4e0a5334730f90118ac26"]
+    }]
+}"#,
+        |client| async move {
+            let body = r#"{"zen":"Keep it logically awesome."}"#;
+            let body_path = dir.join("body.json");
+            std::fs::write(&body_path, body).unwrap();
+            let args = ["send", "/test/send", "-d", body_path.to_str().unwrap()];
+            assert_eq!(run_cli(&dir, &args), body);
+
+            // The same request without a signature is refused.
+            let req = Request::builder()
+                .method("POST")
+                .uri(local_uri(3028, "/test/send"))
+                .body(Body::from(body))
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn check() {
+    let tmp_dir = TempDir::new().unwrap();
+    std::fs::write(
+        tmp_dir.path().join("config.json"),
+        r#"{
+    "port": 3029,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/check",
+        "run": "true",
+        "validate": ["github", "$WEBHOOKEE_TEST_UNSET_VARIABLE"]
+    }, {
+        "methods": ["POST"],
+        "path": "/test/check",
+        "run": "/nonexistent/deploy.sh",
+        "validate": false
+    }]
+}"#,
+    )
+    .unwrap();
+
+    let output = cli(tmp_dir.path(), &["check"]);
+    assert!(!output.status.success());
+    let problems: Vec<_> = std::str::from_utf8(&output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split_once("config.json:").unwrap().1)
+        .collect();
+    assert_eq!(problems.len(), 3);
+    assert!(
+        problems[0].starts_with("5:18: error: Environment variable WEBHOOKEE_TEST_UNSET_VARIABLE")
+    );
+    assert!(problems[1].starts_with("10:18: warning: Catcher for `/test/check` will never be used"));
+    assert!(problems[2]
+        .starts_with("10:18: error: `.run` command `/nonexistent/deploy.sh` does not exist"));
+
+    let dry_run = run_cli(
+        tmp_dir.path(),
+        &["check", "--dry-run", "POST", "/test/check"],
+    );
+    assert!(dry_run.starts_with("`POST` to `/test/check` would be handled by the catcher at "));
+    assert!(dry_run.contains("config.json:5:18\n"));
+
+    // Warnings alone don't make it fail.
+    let tmp_dir = TempDir::new().unwrap();
+    std::fs::write(
+        tmp_dir.path().join("config.json"),
+        r#"{
+    "port": 3029,
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/check",
+        "run": "true",
+        "validate": false
+    }, {
+        "methods": ["POST"],
+        "path": "/test/check",
+        "run": "true",
+        "validate": false
+    }]
+}"#,
+    )
+    .unwrap();
+    let warnings = run_cli(tmp_dir.path(), &["check"]);
+    assert!(warnings.contains("config.json:10:18: warning: Catcher #1 also has the path"));
+
+    let tmp_dir = TempDir::new().unwrap();
+    std::fs::write(
+        tmp_dir.path().join("config.json"),
+        r#"{
+    "port": 3029,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/check",
+        "run": "true",
+        "validate": false,
+        "async": true,
+        "response": "stream"
+    }]
+}"#,
+    )
+    .unwrap();
+    let output = cli(tmp_dir.path(), &["check"]);
+    assert!(!output.status.success());
+    assert!(std::str::from_utf8(&output.stdout)
+        .unwrap()
+        .contains("error: Catcher for `/test/check` is async, so it can't stream its output"));
+}
+
+#[test]
+async fn toml_and_yaml_config() {
+    let toml = r#"
+port = 3030
+
+# Comments are allowed here.
+[[catchers]]
+methods = ["POST"]
+path = "/test/toml"
+run = """jq -j '"toml: " + .body'"""
+validate = false
+"#;
+    let yaml = r#"
+port: 3030
+catchers:
+  # And here.
+  - methods: [POST]
+    path: /test/yaml
+    run: >-
+      jq -j '"yaml: " + .body'
+    validate: false
+"#;
+    for (name, config, path, expected) in [
+        ("config.toml", toml, "/test/toml", "toml: hi"),
+        ("config.yaml", yaml, "/test/yaml", "yaml: hi"),
+    ] {
+        let tmp_dir = TempDir::new().unwrap();
+        invoke_with_config_file(
+            tmp_dir.path(),
+            &[],
+            &[],
+            name,
+            config,
+            |client| async move {
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri(local_uri(3030, path))
+                    .body(Body::from("hi"))
+                    .unwrap();
+                let res = send_req(&client, req).await.unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(full_body(res.into_body()).await, expected);
+            },
+        )
+        .await;
+    }
+}
+
+#[test]
+async fn included_catchers() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(
+        dir.join("extra.toml"),
+        r#"
+[[catchers]]
+methods = ["POST"]
+path = "/test/included"
+run = "printf included"
+validate = false
+"#,
+    )
+    .unwrap();
+    std::fs::create_dir(dir.join("catchers.d")).unwrap();
+    std::fs::write(
+        dir.join("catchers.d").join("project.yaml"),
+        r#"
+catchers:
+  - methods: [POST]
+    path: /test/catchers.d
+    run: printf catchers.d
+    validate: false
+"#,
+    )
+    .unwrap();
+    invoke_in(
+        dir,
+        &[],
+        r#"{
+    "port": 3031,
+    "include": ["extra.toml"],
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/main",
+        "run": "printf main",
+        "validate": false
+    }]
+}"#,
+        |client| async move {
+            for path in ["/test/main", "/test/included", "/test/catchers.d"] {
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri(local_uri(3031, path))
+                    .body(Body::empty())
+                    .unwrap();
+                let res = send_req(&client, req).await.unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                assert_eq!(
+                    full_body(res.into_body()).await,
+                    path.rsplit('/').next().unwrap()
+                );
+            }
+        },
+    )
+    .await;
+
+    // Another file taking over one of the paths is an error.
+    std::fs::write(
+        dir.join("catchers.d").join("other.json"),
+        r#"{"catchers": [{"methods": ["POST", "PUT"], "path": "/test/main", "run": "true", "validate": false}]}"#,
+    )
+    .unwrap();
+    let output = cli(dir, &["check"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout
+        .contains("other.json:1:53: error: Catcher for `/test/main` conflicts with the one in "));
+}
+
+#[test]
+async fn reload_config() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let cfg_file_path = dir.join("config.json");
+    let config = |path: &str| {
+        format!(
+            r#"{{
+    "port": 3032,
+    "catchers": [{{
+        "methods": ["GET"],
+        "path": "{}",
+        "run": "printf reloaded",
+        "validate": false
+    }}]
+}}"#,
+            path
+        )
+    };
+    invoke_with_config_file(
+        dir,
+        &[],
+        &["--watch-config"],
+        "config.json",
+        &config("/test/before"),
+        |client| async move {
+            let status = |path: &'static str| {
+                let client = client.clone();
+                async move { get(&client, local_uri(3032, path)).await.unwrap().status() }
+            };
+            assert_eq!(status("/test/before").await, StatusCode::OK);
+
+            std::fs::write(&cfg_file_path, config("/test/after")).unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert_eq!(status("/test/before").await, StatusCode::NOT_FOUND);
+            assert_eq!(status("/test/after").await, StatusCode::OK);
+
+            // A broken configuration is not switched to.
+            std::fs::write(&cfg_file_path, "{").unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            assert_eq!(status("/test/after").await, StatusCode::OK);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn reload_on_sighup() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let cfg_file_path = dir.join("config.json");
+    // The parent of `.run` is webhookee itself.
+    let config = |path: &str| {
+        format!(
+            r#"{{
+    "port": 3048,
+    "catchers": [{{
+        "methods": ["GET"],
+        "path": "{}",
+        "run": "printf $PPID",
+        "validate": false
+    }}]
+}}"#,
+            path
+        )
+    };
+    invoke_with_config_file(
+        dir,
+        &[],
+        &[],
+        "config.json",
+        &config("/test/before"),
+        |client| async move {
+            let res = get(&client, local_uri(3048, "/test/before")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let pid: libc::pid_t = full_body(res.into_body()).await.parse().unwrap();
+
+            // Without `--watch-config`, changes are only picked up on SIGHUP.
+            std::fs::write(&cfg_file_path, config("/test/after")).unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let status = |path: &'static str| {
+                let client = client.clone();
+                async move { get(&client, local_uri(3048, path)).await.unwrap().status() }
+            };
+            assert_eq!(status("/test/after").await, StatusCode::NOT_FOUND);
+            unsafe { libc::kill(pid, libc::SIGHUP) };
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(status("/test/before").await, StatusCode::NOT_FOUND);
+            assert_eq!(status("/test/after").await, StatusCode::OK);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn interpolation() {
+    invoke_with_env(
+        &[
+            ("WEBHOOKEE_TEST_PORT", "3033"),
+            ("WEBHOOKEE_TEST_GREETING", "hello"),
+        ],
+        r#"{
+    "port": "${WEBHOOKEE_TEST_PORT}",
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/${WEBHOOKEE_TEST_UNSET_VARIABLE:-interpolation}",
+        "run": "printf '%s ${WEBHOOKEE_TEST_GREETING}' \"$WEBHOOKEE_TEST_GREETING\"",
+        "validate": false
+    }]
+}"#,
+        |client| async move {
+            let res = get(&client, local_uri(3033, "/test/interpolation"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            // Commands are left to the shell.
+            assert_eq!(
+                full_body(res.into_body()).await,
+                "hello ${WEBHOOKEE_TEST_GREETING}"
+            );
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn multiple_listeners() {
+    invoke(
+        r#"{
+    "listen": ["127.0.0.1:3034", "127.0.0.1:3035"],
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/public",
+        "run": "printf public",
+        "validate": false
+    }, {
+        "methods": ["GET"],
+        "path": "/test/internal",
+        "run": "printf internal",
+        "validate": false,
+        "listen": ["127.0.0.1:3035"]
+    }]
+}"#,
+        |client| async move {
+            let status = |uri: &'static str| {
+                let client = client.clone();
+                async move { get(&client, uri.parse().unwrap()).await.unwrap().status() }
+            };
+            assert_eq!(
+                status("http://127.0.0.1:3034/test/public").await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status("http://127.0.0.1:3035/test/public").await,
+                StatusCode::OK
+            );
+            assert_eq!(
+                status("http://127.0.0.1:3034/test/internal").await,
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(
+                status("http://127.0.0.1:3035/test/internal").await,
+                StatusCode::OK
+            );
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::AsyncReadExt;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let socket_path = dir.join("webhookee.sock");
+    let config = format!(
+        r#"{{
+    "listen": [{{"unix": "{}", "mode": "600"}}],
+    "catchers": [{{
+        "methods": ["GET"],
+        "path": "/test/unix",
+        "run": "printf unix",
+        "validate": false
+    }}]
+}}"#,
+        socket_path.display()
+    );
+    invoke_in(dir, &[], &config, |_client| async move {
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream
+            .write_all(b"GET /test/unix HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nunix"));
+    })
+    .await;
+}
+
+#[test]
+async fn socket_activation() {
+    use std::os::unix::{io::AsRawFd, process::CommandExt};
+
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(
+        dir.join("config.json"),
+        r#"{
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/socket_activation",
+        "run": "printf activated",
+        "validate": false,
+        "listen": ["systemd:webhooks"]
+    }]
+}"#,
+    )
+    .unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:3036").unwrap();
+    let fd = listener.as_raw_fd();
+    let notify_socket_path = dir.join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_socket_path).unwrap();
+    // Like systemd, pass the socket as file descriptor 3, with LISTEN_PID set
+    // to the PID of webhookee itself.
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(assert_cmd::cargo::cargo_bin("webhookee"))
+        .arg("--config")
+        .arg(dir.join("config.json"))
+        .arg("--log-file")
+        .arg(dir.join("log"))
+        .arg("--data-dir")
+        .arg(dir.join("data"))
+        .env("LISTEN_FDS", "1")
+        .env("LISTEN_FDNAMES", "webhooks")
+        .env("NOTIFY_SOCKET", &notify_socket_path);
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, 3) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().expect("Failed to execute webhookee");
+    drop(listener);
+
+    wait_until_ready(&notify_socket).await;
+    let res = get(&Client::new(), local_uri(3036, "/test/socket_activation")).await;
+    let _ = child.kill();
+    let _ = child.wait();
+    let res = res.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(full_body(res.into_body()).await, "activated");
+}
+
+#[test]
+async fn graceful_shutdown() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(
+        dir.join("config.json"),
+        r#"{
+    "listen": ["127.0.0.1:3039"],
+    "shutdown_timeout": 1.5,
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/shutdown/quick",
+        "run": "sleep 0.5; printf finished",
+        "validate": false
+    }, {
+        "methods": ["GET"],
+        "path": "/test/shutdown/slow",
+        "run": "sleep 30",
+        "validate": false,
+        "async": true
+    }]
+}"#,
+    )
+    .unwrap();
+    let notify_socket_path = dir.join("notify.sock");
+    let notify_socket = UnixDatagram::bind(&notify_socket_path).unwrap();
+    let mut child = Command::new(assert_cmd::cargo::cargo_bin("webhookee"))
+        .arg("--config")
+        .arg(dir.join("config.json"))
+        .arg("--log-file")
+        .arg(dir.join("log"))
+        .arg("--data-dir")
+        .arg(dir.join("data"))
+        .env("NOTIFY_SOCKET", &notify_socket_path)
+        .spawn()
+        .expect("Failed to execute webhookee");
+    wait_until_ready(&notify_socket).await;
+
+    let client = Client::new();
+    let res = get(&client, local_uri(3039, "/test/shutdown/slow"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let quick =
+        tokio::spawn(async move { get(&client, local_uri(3039, "/test/shutdown/quick")).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+
+    // The request being handled is finished, but the slow job is terminated
+    // after `.shutdown_timeout` and left in the queue.
+    let res = quick.await.unwrap().unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(full_body(res.into_body()).await, "finished");
+    let mut status = None;
+    for _ in 0..50 {
+        status = child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    if status.is_none() {
+        let _ = child.kill();
+        panic!("webhookee did not stop");
+    }
+    assert_eq!(status.unwrap().code(), Some(0));
+    let queue_dir = dir.join("data").join("queue");
+    assert_eq!(std::fs::read_dir(&queue_dir).unwrap().count(), 1);
+}
+
+#[test]
+async fn health_checks() {
+    invoke(
+        r#"{
+    "port": 3040,
+    "health": {"ready": "/ready"},
+    "catchers": []
+}"#,
+        |client| async move {
+            let res = get(&client, local_uri(3040, "/healthz")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let health: serde_json::Value =
+                serde_json::from_str(&full_body(res.into_body()).await).unwrap();
+            assert_eq!(health["ready"], true);
+            assert_eq!(health["queue"]["depth"], 0);
+            assert_eq!(health["queue"]["writable"], true);
+            assert_eq!(health["config"]["reload_failed"], false);
+
+            let res = get(&client, local_uri(3040, "/ready")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = get(&client, local_uri(3040, "/readyz")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            // Metrics are off unless asked for.
+            let res = get(&client, local_uri(3040, "/metrics")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn metrics() {
+    invoke(
+        r#"{
+    "port": 3041,
+    "metrics": {},
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/metrics",
+        "run": "exit 3",
+        "validate": false
+    }]
+}"#,
+        |client| async move {
+            get(&client, local_uri(3041, "/test/metrics"))
+                .await
+                .unwrap();
+            let res = get(&client, local_uri(3041, "/test/nothing"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            let res = get(&client, local_uri(3041, "/metrics")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let metrics = full_body(res.into_body()).await;
+            for line in &[
+                r#"webhookee_requests_total{catcher="/test/metrics"} 1"#,
+                r#"webhookee_validations_total{catcher="/test/metrics",result="passed"} 1"#,
+                r#"webhookee_runs_total{catcher="/test/metrics",exit_code="3"} 1"#,
+                r#"webhookee_run_duration_seconds_count{catcher="/test/metrics"} 1"#,
+                "webhookee_not_found_total 1",
+                "webhookee_queue_depth 0",
+                "webhookee_running_commands 0",
+            ] {
+                assert!(
+                    metrics.lines().any(|l| l == *line),
+                    "{} not in:\n{}",
+                    line,
+                    metrics
+                );
+            }
+
+            // Health checks are off unless asked for.
+            let res = get(&client, local_uri(3041, "/healthz")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn rate_limit() {
+    invoke(
+        r#"{
+    "port": 3046,
+    "metrics": {"path": "/-/metrics"},
+    "rate_limit": {"rate": 100},
+    "catchers": [{
+        "methods": ["GET"],
+        "path": "/test/limited",
+        "run": "echo hi",
+        "validate": "exit 0",
+        "rate_limit": {"rate": 0.1, "burst": 2}
+    }, {
+        "methods": ["GET"],
+        "path": "/test/unlimited",
+        "run": "echo hi",
+        "validate": false
+    }]
+}"#,
+        |client| async move {
+            for _ in 0..2 {
+                let res = get(&client, local_uri(3046, "/test/limited"))
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            let res = get(&client, local_uri(3046, "/test/limited"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: u64 = res.headers()["retry-after"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=10).contains(&retry_after));
+
+            let res = get(&client, local_uri(3046, "/test/unlimited"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let res = get(&client, local_uri(3046, "/-/metrics")).await.unwrap();
+            let metrics = full_body(res.into_body()).await;
+            assert!(metrics
+                .lines()
+                .any(|l| l == r#"webhookee_rate_limited_total{catcher="/test/limited"} 1"#));
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn size_limits() {
+    invoke(
+        r#"{
+    "port": 3047,
+    "max_body_size": 10,
+    "max_headers": 5,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/small",
+        "run": "jq -j .body",
+        "validate": false
+    }, {
+        "methods": ["POST"],
+        "path": "/test/big",
+        "run": "jq -j .body",
+        "validate": false,
+        "max_body_size": 100
+    }]
+}"#,
+        |client| async move {
+            let post = |path, body| {
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(local_uri(3047, path))
+                    .body(body)
+                    .unwrap()
+            };
+            let res = send_req(&client, post("/test/small", Body::from("just right")))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = send_req(&client, post("/test/small", Body::from("a bit too big")))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let res = send_req(&client, post("/test/big", Body::from("a bit too big")))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(full_body(res.into_body()).await, "a bit too big");
+
+            // Without a `Content-Length`.
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    let _ = sender.send_data("chunk".into()).await;
+                }
+            });
+            let res = send_req(&client, post("/test/small", body)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let mut req = post("/test/small", Body::from("hi"));
+            for i in 0..5 {
+                let name: hyper::header::HeaderName = format!("x-header-{}", i).parse().unwrap();
+                req.headers_mut().insert(name, "hi".parse().unwrap());
+            }
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        },
+    )
+    .await;
+}
+
+#[test]
+async fn json_logs() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    invoke_with_config_file(
+        dir,
+        &[],
+        &["--log-format", "json"],
+        "config.json",
+        r#"{
+    "port": 3042,
+    "catchers": [{
+        "methods": ["POST"],
+        "path": "/test/json_logs",
+        "run": "true",
+        "validate": "false"
+    }, {
+        "methods": ["POST"],
+        "path": "/test/json_logs/async",
+        "run": "true",
+        "validate": false,
+        "async": true
+    }]
+}"#,
+        |client| async move {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(local_uri(3042, "/test/json_logs"))
+                .body(Body::empty())
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(local_uri(3042, "/test/json_logs/async"))
+                .body(Body::empty())
+                .unwrap();
+            let res = send_req(&client, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            for _ in 0..20 {
+                let logs = std::fs::read_to_string(dir.join("log")).unwrap();
+                if logs.contains("finished (attempt 1/1)") {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        },
+    )
+    .await;
+
+    let logs = std::fs::read_to_string(dir.join("log")).unwrap();
+    let lines: Vec<serde_json::Value> = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let denied = lines
+        .iter()
+        .find(|line| {
+            line["message"]
+                .as_str()
+                .unwrap()
+                .contains("failed validation")
+        })
+        .unwrap();
+    let answered = lines
+        .iter()
+        .find(|line| line["message"].as_str().unwrap().contains("answered with"))
+        .unwrap();
+    assert_eq!(answered["level"], "INFO");
+    assert_eq!(answered["request_id"], denied["request_id"]);
+    assert_eq!(answered["request_id"].as_str().unwrap().len(), 16);
+    assert_eq!(answered["method"], "POST");
+    assert_eq!(answered["path"], "/test/json_logs");
+    assert_eq!(answered["catcher"], "/test/json_logs");
+    assert!(answered["remote_addr"]
+        .as_str()
+        .unwrap()
+        .starts_with("127.0.0.1:"));
+    assert_eq!(answered["validation"], "failed");
+    assert_eq!(answered["status"], 403);
+    assert!(answered["duration_ms"].is_u64());
+    // The job goes on after the response, which shouldn't show up on its
+    // lines.
+    let finished = lines
+        .iter()
+        .find(|line| {
+            line["message"]
+                .as_str()
+                .unwrap()
+                .contains("finished (attempt 1/1)")
+        })
+        .unwrap();
+    assert_eq!(finished["catcher"], "/test/json_logs/async");
+    assert!(finished.get("status").is_none(), "{}", finished);
+    assert!(finished.get("duration_ms").is_none(), "{}", finished);
+}
+
+#[test]
+async fn log_level_and_append() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    std::fs::write(dir.join("log"), "previous run\n").unwrap();
+    invoke_with_config_file(
+        dir,
+        &[],
+        &["--log-level", "warn"],
+        "config.json",
+        r#"{
+    "port": 3043,
+    "log": {"level": "debug"},
+    "catchers": []
+}"#,
+        |client| async move {
+            let res = get(&client, local_uri(3043, "/test/nothing"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        },
+    )
+    .await;
+    let logs = std::fs::read_to_string(dir.join("log")).unwrap();
+    assert!(logs.starts_with("previous run\n"), "{}", logs);
+    assert!(
+        !logs.contains("[INFO]") && !logs.contains("[DEBUG]"),
+        "{}",
+        logs
+    );
+}
+
+#[test]
+async fn log_rotation() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    invoke_in(
+        dir,
+        &[],
+        r#"{
+    "port": 3044,
+    "log": {"max_size": 150, "keep": 2},
+    "catchers": []
+}"#,
+        |client| async move {
+            for _ in 0..5 {
+                get(&client, local_uri(3044, "/test/nothing"))
+                    .await
+                    .unwrap();
+            }
+        },
+    )
+    .await;
+    let rotated = |n: usize| dir.join(format!("log.{}", n));
+    assert!(rotated(1).exists());
+    assert!(rotated(2).exists());
+    assert!(!rotated(3).exists());
+    for path in &[dir.join("log"), rotated(1), rotated(2)] {
+        let logs = std::fs::read_to_string(path).unwrap();
+        // Lines aren't split between files.
+        assert!(logs.ends_with('\n'), "{}", logs);
+        assert!(
+            logs.lines().all(|line| line.contains(" [INFO] ")),
+            "{}",
+            logs
+        );
+    }
+}
+
+#[test]
+async fn access_log() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    let access_log = dir.join("access.log");
+    let config = format!(
+        r#"{{
+    "port": 3045,
+    "access_log": {{"file": "{}"}},
+    "catchers": [{{
+        "methods": ["POST"],
+        "path": "/test/access_log",
+        "run": "printf hello",
+        "validate": false
+    }}]
+}}"#,
+        access_log.display()
+    );
+    invoke_in(dir, &[], &config, |client| async move {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(local_uri(3045, "/test/access_log?x=1"))
+            .header("user-agent", "test \"agent\"")
+            .header("referer", "http://example.com/")
+            .body(Body::empty())
+            .unwrap();
+        let res = send_req(&client, req).await.unwrap();
+        assert_eq!(full_body(res.into_body()).await, "hello");
+        let res = get(&client, local_uri(3045, "/test/nothing"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        // Requests are logged once the response has been sent.
+        for _ in 0..20 {
+            let logs = std::fs::read_to_string(&access_log).unwrap();
+            if logs.lines().count() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+
+    let logs = std::fs::read_to_string(dir.join("access.log")).unwrap();
+    let lines: Vec<&str> = logs.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", logs);
+    // The time is between the brackets.
+    let without_time = |line: &str| {
+        let (start, rest) = line.split_once(" [").unwrap();
+        let (_, end) = rest.split_once("] ").unwrap();
+        format!("{} {}", start, end)
+    };
+    // Then the duration and the catcher.
+    let first = without_time(lines[0]);
+    let (first, catcher) = first.rsplit_once(' ').unwrap();
+    let (first, duration) = first.rsplit_once(' ').unwrap();
+    assert_eq!(
+        first,
+        r#"127.0.0.1 - - "POST /test/access_log?x=1 HTTP/1.1" 200 5 "http://example.com/" "test \x22agent\x22""#
+    );
+    assert_eq!(catcher, "\"/test/access_log\"");
+    assert!(duration.parse::<f64>().is_ok(), "{}", duration);
+    assert!(
+        without_time(lines[1])
+            .starts_with(r#"127.0.0.1 - - "GET /test/nothing HTTP/1.1" 404 - "-" "-" "#),
+        "{}",
+        lines[1]
+    );
+    assert!(lines[1].ends_with(" \"-\""), "{}", lines[1]);
+}
+
+#[test]
+async fn tls() {
+    use std::{convert::TryFrom, sync::Arc};
+    use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+    let tls_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("tls");
+    let config = format!(
+        r#"{{
+    "listen": ["127.0.0.1:3037"],
+    "tls": {{"cert": "{}", "key": "{}"}},
+    "catchers": [{{
+        "methods": ["GET"],
+        "path": "/test/tls",
+        "run": "printf secure",
+        "validate": false
+    }}]
+}}"#,
+        tls_dir.join("server.pem").display(),
+        tls_dir.join("server.key").display()
+    );
+    invoke(&config, |_client| async move {
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(tls_dir.join("ca.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut &ca[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(
+            ClientConfig::builder_with_provider(Arc::new(
+                tokio_rustls::rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+        ));
+        let tcp = tokio::net::TcpStream::connect("127.0.0.1:3037")
+            .await
+            .unwrap();
+        let stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .uri("/test/tls")
+            .header("host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(full_body(res.into_body()).await, "secure");
+    })
+    .await;
+}
+
+#[test]
+async fn client_cert() {
+    use std::{convert::TryFrom, sync::Arc};
+    use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+
+    let tls_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("tls");
+    let config = format!(
+        r#"{{
+    "listen": ["127.0.0.1:3038"],
+    "tls": {{"cert": "{}", "key": "{}", "client_ca": "{}"}},
+    "catchers": [{{
+        "methods": ["GET"],
+        "path": "/test/mtls",
+        "run": "grep -o '\"san\":\\[[^]]*\\]'",
+        "validate": ["mtls", "deployer"]
+    }}, {{
+        "methods": ["GET"],
+        "path": "/test/mtls/other",
+        "run": "true",
+        "validate": ["mtls", "someone-else"]
+    }}]
+}}"#,
+        tls_dir.join("server.pem").display(),
+        tls_dir.join("server.key").display(),
+        tls_dir.join("ca.pem").display()
+    );
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path().to_owned();
+    invoke_in(tmp_dir.path(), &[], &config, |_client| async move {
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(tls_dir.join("ca.pem")).unwrap();
+        for cert in rustls_pemfile::certs(&mut &ca[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let cert = std::fs::read(tls_dir.join("client.pem")).unwrap();
+        let cert: Vec<_> = rustls_pemfile::certs(&mut &cert[..])
+            .map(Result::unwrap)
+            .collect();
+        let key = std::fs::read(tls_dir.join("client.key")).unwrap();
+        let key = rustls_pemfile::private_key(&mut &key[..]).unwrap().unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(
+            tokio_rustls::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+        let with_cert = builder.clone().with_client_auth_cert(cert, key).unwrap();
+        let without_cert = builder.with_no_client_auth();
+
+        let get = |client_config: ClientConfig, path: &'static str| async move {
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+            let tcp = tokio::net::TcpStream::connect("127.0.0.1:3038")
+                .await
+                .unwrap();
+            let stream = connector
+                .connect(ServerName::try_from("localhost").unwrap(), tcp)
+                .await
+                .unwrap();
+            let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+            tokio::spawn(conn);
+            let req = Request::builder()
+                .uri(path)
+                .header("host", "localhost")
+                .body(Body::empty())
+                .unwrap();
+            sender.send_request(req).await.unwrap()
+        };
+
+        let res = get(with_cert.clone(), "/test/mtls").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            full_body(res.into_body()).await,
+            "\"san\":[\"deployer@example.com\"]\n"
+        );
+        let res = get(without_cert, "/test/mtls").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = get(with_cert, "/test/mtls/other").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // `send` connects over HTTPS too.
+        let tls_file = |name: &str| tls_dir.join(name).to_str().unwrap().to_owned();
+        let args = [
+            "send",
+            "/test/mtls",
+            "-X",
+            "GET",
+            "--cacert",
+            &tls_file("ca.pem"),
+            "--cert",
+            &tls_file("client.pem"),
+            "--key",
+            &tls_file("client.key"),
+        ];
+        assert_eq!(run_cli(&dir, &args), "\"san\":[\"deployer@example.com\"]\n");
+        assert!(!cli(&dir, &args[..6]).status.success());
+    })
+    .await;
+}